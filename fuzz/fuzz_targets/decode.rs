@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// the binary crate has no library, the codec builds on its own
+#[path = "../../src/msg.rs"]
+mod msg;
+
+use msg::Msg;
+
+fuzz_target!(|data: &[u8]| {
+    // whatever decodes is a message the senders could have encoded
+    if let Some(msg) = Msg::decode(data) {
+        assert!(Msg::decode(&msg.encode()).is_some());
+    }
+});
@@ -0,0 +1,121 @@
+use std::fs;
+use std::str::FromStr;
+
+/// Burn rate as a function of the simulation time in seconds
+#[derive(Clone, Debug)]
+pub enum DemandProfile {
+    Constant(f32),
+    /// Day/night cycle around `base`
+    Sine {
+        base: f32,
+        amplitude: f32,
+        period: f32,
+    },
+    /// `(since, rate)` pairs, each rate holds until the next one
+    Step(Vec<(f32, f32)>),
+    /// `(time, rate)` points, linearly interpolated
+    Curve(Vec<(f32, f32)>),
+}
+
+impl DemandProfile {
+    /// Parse `constant:0.3`, `sine:base,amplitude,period`,
+    /// `step:0=0.2,30=0.6,60=0.1` or `csv:path/to/curve.csv`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.splitn(2, ':');
+        let kind = parts.next().unwrap_or_default();
+        let args = parts.next().unwrap_or_default();
+        match kind {
+            "constant" => Ok(DemandProfile::Constant(Self::number(args)?)),
+            "sine" => {
+                let v = args
+                    .split(',')
+                    .map(Self::number)
+                    .collect::<Result<Vec<_>, _>>()?;
+                match v[..] {
+                    [base, amplitude, period] if period > 0.0 => Ok(DemandProfile::Sine {
+                        base,
+                        amplitude,
+                        period,
+                    }),
+                    _ => Err(format!(
+                        "expected sine:base,amplitude,period, got '{}'",
+                        args
+                    )),
+                }
+            }
+            "step" => Ok(DemandProfile::Step(Self::points(
+                args.split(',').map(|p| p.splitn(2, '=')),
+            )?)),
+            "csv" => {
+                let text = fs::read_to_string(args).map_err(|e| format!("{}: {}", args, e))?;
+                let rows = text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    // skip a header
+                    .skip_while(|l| Self::number(l.split(',').next().unwrap_or_default()).is_err());
+                Ok(DemandProfile::Curve(Self::points(
+                    rows.map(|l| l.splitn(2, ',')),
+                )?))
+            }
+            _ => Err(format!("unknown demand profile '{}'", text)),
+        }
+    }
+
+    fn number(s: &str) -> Result<f32, String> {
+        match f32::from_str(s.trim()) {
+            Ok(n) if n.is_finite() => Ok(n),
+            Ok(_) => Err(format!("'{}' is not a finite number", s)),
+            Err(_) => Err(format!("can't parse number '{}'", s)),
+        }
+    }
+
+    fn points<'a, I, P>(pairs: I) -> Result<Vec<(f32, f32)>, String>
+    where
+        I: Iterator<Item = P>,
+        P: Iterator<Item = &'a str>,
+    {
+        let mut points = Vec::new();
+        for mut pair in pairs {
+            match (pair.next(), pair.next()) {
+                (Some(t), Some(v)) => points.push((Self::number(t)?, Self::number(v)?)),
+                _ => return Err("expected time and rate pairs".to_string()),
+            }
+        }
+        if points.is_empty() {
+            return Err("empty demand profile".to_string());
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(points)
+    }
+
+    /// Rate at the moment `t`, never negative
+    pub fn at(&self, t: f32) -> f32 {
+        let rate = match self {
+            DemandProfile::Constant(rate) => *rate,
+            DemandProfile::Sine {
+                base,
+                amplitude,
+                period,
+            } => base + amplitude * (t / period * 2.0 * std::f32::consts::PI).sin(),
+            DemandProfile::Step(points) => {
+                points
+                    .iter()
+                    .take_while(|p| p.0 <= t)
+                    .last()
+                    .unwrap_or(&points[0])
+                    .1
+            }
+            DemandProfile::Curve(points) => match points.iter().position(|p| p.0 > t) {
+                Some(0) => points[0].1,
+                Some(i) => {
+                    let (t0, v0) = points[i - 1];
+                    let (t1, v1) = points[i];
+                    v0 + (v1 - v0) * (t - t0) / (t1 - t0)
+                }
+                None => points[points.len() - 1].1,
+            },
+        };
+        f32::max(rate, 0.0)
+    }
+}
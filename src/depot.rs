@@ -0,0 +1,164 @@
+use crate::ledger;
+use crate::mine::{MineStock, MineStore};
+use crate::posixmq::{self, Msg, DEPOT_DISPATCH_QUEUE, DEPOT_QUEUE_PREFIX, PMQ};
+use crate::scenario::DepotConfig;
+use nannou::prelude::*;
+use nannou::ui::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEPOT_WIDTH: f64 = 40.0;
+/// Gauge height in pixels per unit of the depot capacity
+const GAUGE_SCALE: f64 = 0.5;
+/// Seconds the fuel drawn by the local tanks is averaged over
+const DRAW_WINDOW: f32 = 30.0;
+
+/// Fuel store between the mines and the stations: the long-haul tanks deliver
+/// to it like to a station and the local tanks load at it like at a mine
+pub struct Depot {
+    id: widget::Id,
+    label: String,
+    capacity: f32,
+    height: f64,
+    store: MineStore,
+}
+
+impl Depot {
+    pub fn new(idx: usize, id: widget::Id, config: &DepotConfig) -> Self {
+        let store = MineStore::local(MineStock {
+            fuel: config.fuel,
+            speed: 0.0,
+            reserve: None,
+            loading: 0,
+            waiting: 0,
+        });
+        let depot = Depot {
+            id,
+            label: "0".to_string(),
+            capacity: config.capacity,
+            height: config.capacity as f64 * GAUGE_SCALE,
+            store,
+        };
+        ledger::produce(config.fuel);
+        let config = config.clone();
+        let store = depot.store();
+        thread::spawn(move || Self::run(idx, config, store));
+        depot
+    }
+
+    /// Shared stock, the local tanks use it to choose where to load
+    pub fn store(&self) -> MineStore {
+        self.store.clone()
+    }
+
+    /// Draw the gauge with its bottom right corner at the map `node` on the screen
+    pub fn update_ui(&mut self, ui: &mut UiCell, node: Point2) {
+        let fuel = self.store.fuel();
+        self.label = format!("{:.0}", fuel);
+        widget::Slider::new(fuel, 0., self.capacity)
+            .label(&self.label)
+            .enabled(false)
+            .w_h(DEPOT_WIDTH, self.height)
+            .label_font_size(14)
+            .rgb(0.9, 0.6, 0.2)
+            .label_rgb(1.0, 1.0, 1.0)
+            .border(0.3)
+            .x_y(
+                node.x as f64 - DEPOT_WIDTH / 2.0,
+                node.y as f64 + self.height / 2.0,
+            )
+            .set(self.id, ui);
+    }
+
+    fn run(idx: usize, config: DepotConfig, store: MineStore) {
+        info!("Build depot #{}", idx);
+        let delay = Duration::from_millis(50);
+        let q_name = format!("{}{}", DEPOT_QUEUE_PREFIX, idx);
+        let q = PMQ::open(q_name.as_ref()).nonblocking();
+        let mq_dispatch = PMQ::open(DEPOT_DISPATCH_QUEUE);
+        let mut mq_v: HashMap<usize, PMQ> = HashMap::new();
+        // tank and the fuel it still wants, one loads at a time
+        let mut waiting: VecDeque<(usize, f32)> = VecDeque::new();
+        let mut requested = false;
+        // fuel per second the local tanks load, the drain rate the haulers see
+        let mut draw = 0.0;
+        let mut last = Instant::now();
+        loop {
+            while let Ok(msg) = q.receive() {
+                trace!("Depot #{} receive msg: {:?}", idx, msg);
+                let tank = match msg {
+                    Msg::Request(tank, _) | Msg::Delivery(tank, _) => tank,
+                    msg => {
+                        error!("Unsupported message for depot: {:?}", msg);
+                        continue;
+                    }
+                };
+                let mq = mq_v
+                    .entry(tank)
+                    .or_insert_with(|| PMQ::open(posixmq::vehicle_queue(tank).as_ref()));
+                match msg {
+                    Msg::Request(tank, amount) => waiting.push_back((tank, amount)),
+                    Msg::Delivery(_, amount) if amount > 0.0 => {
+                        let mut stock = store.lock();
+                        let before = stock.fuel;
+                        let remain = f32::max(stock.fuel + amount - config.capacity, 0.0);
+                        stock.fuel += amount - remain;
+                        ledger::transfer(stock.fuel - before, remain - amount);
+                        if remain > 0.0 {
+                            mq.send(Msg::Fuel(remain)).expect("Send remain tank fuel");
+                        } else {
+                            mq.send(Msg::TankUnload).expect("Send tank unload");
+                        }
+                    }
+                    _ => {
+                        mq.send(Msg::TankMove).expect("Send TankMove from depot");
+                        // ask again if the delivery was not enough
+                        requested = false;
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            let dt = now.duration_since(last);
+            last = now;
+            let dt = dt.as_secs() as f32 + dt.subsec_millis() as f32 / 1000.0;
+            let mut stock = store.lock();
+            let mut drawn = 0.0;
+            if let Some((tank, left)) = waiting.front_mut() {
+                let val = f32::min(*left, config.transfer_rate * dt);
+                let val = f32::min(val, stock.fuel);
+                if val > 0.0 {
+                    drawn = val;
+                    stock.fuel -= val;
+                    *left -= val;
+                    ledger::transfer(-val, val);
+                    mq_v[tank]
+                        .send(Msg::Fuel(val))
+                        .expect("Send fuel to vehicle");
+                }
+                if *left <= 0.0 || stock.fuel <= 0.0 {
+                    mq_v[tank]
+                        .send(Msg::TankMove)
+                        .expect("Send move to vehicle");
+                    waiting.pop_front();
+                }
+            }
+            if dt > 0.0 {
+                draw += (drawn / dt - draw) * f32::min(dt / DRAW_WINDOW, 1.0);
+            }
+            stock.loading = if waiting.is_empty() { 0 } else { 1 };
+            stock.waiting = waiting.len().saturating_sub(1) as u32;
+
+            if stock.fuel <= config.low && !requested {
+                info!("Depot #{} is low on fuel: {:.1}", idx, stock.fuel);
+                mq_dispatch
+                    .send(Msg::IdleStation(idx, stock.fuel, draw))
+                    .expect("Send idle depot");
+                requested = true;
+            }
+            drop(stock);
+            thread::sleep(delay);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use crate::tank::TankState;
+use nannou::prelude::*;
+use std::cmp::Ordering;
+use std::time::{Duration, Instant};
+
+// stations burn their rate every tick
+const STATION_TICK: Duration = Duration::from_millis(100);
+
+/// Names of the built-in strategies, in the order of `by_index`
+pub const STRATEGIES: [&str; 4] = ["FIFO", "Round robin", "Lowest fuel", "Earliest empty"];
+
+/// Names of the mine choices, in the order of `MinePolicy::by_index`
+pub const MINE_POLICIES: [&str; 2] = ["Fullest mine", "Nearest mine"];
+
+/// Which mine a tank loads at next
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MinePolicy {
+    /// The largest stock
+    Fullest,
+    /// The shortest straight line from the tank, the fullest one without the map
+    Nearest,
+}
+
+impl MinePolicy {
+    /// The policy with the index in `MINE_POLICIES`
+    pub fn by_index(idx: usize) -> Self {
+        match idx {
+            0 => MinePolicy::Fullest,
+            _ => MinePolicy::Nearest,
+        }
+    }
+}
+
+/// Refuel request of a station, as reported when its fuel got low
+#[derive(Copy, Clone, Debug)]
+pub struct StationRequest {
+    pub level: f32,
+    pub rate: f32,
+    pub since: Instant,
+}
+
+impl StationRequest {
+    /// Estimated ticks until the station runs out of fuel
+    pub fn time_to_empty(&self) -> f32 {
+        if self.rate <= 0.0 {
+            return f32::INFINITY;
+        }
+        let elapsed = self.since.elapsed();
+        let ticks = (elapsed.as_secs() as f32 + elapsed.subsec_millis() as f32 / 1e3)
+            / (STATION_TICK.subsec_millis() as f32 / 1e3);
+        f32::max(self.level / self.rate - ticks, 0.0)
+    }
+}
+
+/// A station waiting for fuel
+#[derive(Copy, Clone, Debug)]
+pub struct Candidate {
+    pub idx: usize,
+    pub request: StationRequest,
+    /// Position on the map in world units
+    pub position: Option<Point2>,
+}
+
+/// What the strategy knows about the tank
+#[derive(Copy, Clone, Debug)]
+pub struct TankView {
+    pub state: TankState,
+    pub fuel: f32,
+    pub capacity: f32,
+    pub position: Option<Point2>,
+}
+
+/// Map positions shared by the vehicle with the tank worker
+#[derive(Clone, Debug, Default)]
+pub struct Positions {
+    pub tank: Option<Point2>,
+    pub stations: Vec<Point2>,
+    /// The nodes the tank loads at, the mines or the depots
+    pub sources: Vec<Point2>,
+}
+
+pub trait DispatchStrategy: Send {
+    fn name(&self) -> &'static str;
+    /// Pick the next station to supply from the `candidates` sorted by index
+    fn next(&mut self, candidates: &[Candidate], tank: &TankView) -> Option<usize>;
+}
+
+/// Build the strategy with the index in `STRATEGIES`
+pub fn by_index(idx: usize) -> Box<dyn DispatchStrategy> {
+    match idx {
+        0 => Box::new(Fifo),
+        1 => Box::new(RoundRobin { last: None }),
+        2 => Box::new(LowestFuel),
+        _ => Box::new(EarliestEmpty),
+    }
+}
+
+/// Ascending order with NaN last, a bad report never wins
+fn ascending(a: f32, b: f32) -> Ordering {
+    a.partial_cmp(&b)
+        .unwrap_or_else(|| a.is_nan().cmp(&b.is_nan()))
+}
+
+/// The station which asked first
+pub struct Fifo;
+
+impl DispatchStrategy for Fifo {
+    fn name(&self) -> &'static str {
+        STRATEGIES[0]
+    }
+
+    fn next(&mut self, candidates: &[Candidate], _tank: &TankView) -> Option<usize> {
+        candidates
+            .iter()
+            .min_by_key(|c| c.request.since)
+            .map(|c| c.idx)
+    }
+}
+
+/// The next station by index after the last chosen one
+pub struct RoundRobin {
+    last: Option<usize>,
+}
+
+impl DispatchStrategy for RoundRobin {
+    fn name(&self) -> &'static str {
+        STRATEGIES[1]
+    }
+
+    fn next(&mut self, candidates: &[Candidate], _tank: &TankView) -> Option<usize> {
+        let next = match self.last {
+            Some(last) => candidates
+                .iter()
+                .find(|c| c.idx > last)
+                .or_else(|| candidates.first()),
+            None => candidates.first(),
+        }
+        .map(|c| c.idx);
+        if next.is_some() {
+            self.last = next;
+        }
+        next
+    }
+}
+
+/// The station with the least fuel, the closest one wins a tie
+pub struct LowestFuel;
+
+impl DispatchStrategy for LowestFuel {
+    fn name(&self) -> &'static str {
+        STRATEGIES[2]
+    }
+
+    fn next(&mut self, candidates: &[Candidate], tank: &TankView) -> Option<usize> {
+        let distance = |c: &Candidate| match (tank.position, c.position) {
+            (Some(t), Some(p)) => t.distance(p),
+            _ => 0.0,
+        };
+        candidates
+            .iter()
+            .min_by(|a, b| {
+                ascending(a.request.level, b.request.level)
+                    .then(ascending(distance(a), distance(b)))
+            })
+            .map(|c| c.idx)
+    }
+}
+
+/// The station which will run out of fuel first
+pub struct EarliestEmpty;
+
+impl DispatchStrategy for EarliestEmpty {
+    fn name(&self) -> &'static str {
+        STRATEGIES[3]
+    }
+
+    fn next(&mut self, candidates: &[Candidate], _tank: &TankView) -> Option<usize> {
+        candidates
+            .iter()
+            .map(|c| (c.idx, c.request.time_to_empty()))
+            .min_by(|a, b| ascending(a.1, b.1).then(a.0.cmp(&b.0)))
+            .map(|(idx, _)| idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TANK: TankView = TankView {
+        state: TankState::Load(100.0),
+        fuel: 20.0,
+        capacity: 20.0,
+        position: None,
+    };
+
+    fn candidate(idx: usize, level: f32, rate: f32) -> Candidate {
+        Candidate {
+            idx,
+            request: StationRequest {
+                level,
+                rate,
+                since: Instant::now(),
+            },
+            position: None,
+        }
+    }
+
+    #[test]
+    fn fifo_picks_the_first_request() {
+        let mut first = candidate(4, 1.0, 0.1);
+        let second = candidate(2, 1.0, 0.1);
+        first.request.since = second.request.since - Duration::from_secs(1);
+        assert_eq!(Fifo.next(&[second, first], &TANK), Some(4));
+        assert_eq!(Fifo.next(&[], &TANK), None);
+    }
+
+    #[test]
+    fn round_robin_wraps_around() {
+        let candidates = [
+            candidate(1, 1.0, 0.1),
+            candidate(3, 1.0, 0.1),
+            candidate(5, 1.0, 0.1),
+        ];
+        let mut strategy = RoundRobin { last: None };
+        let picks: Vec<_> = (0..4).map(|_| strategy.next(&candidates, &TANK)).collect();
+        assert_eq!(picks, vec![Some(1), Some(3), Some(5), Some(1)]);
+        // a station which stopped asking is skipped
+        assert_eq!(strategy.next(&candidates[1..], &TANK), Some(3));
+    }
+
+    #[test]
+    fn lowest_fuel_breaks_a_tie_by_distance() {
+        let mut near = candidate(2, 1.0, 0.1);
+        let mut far = candidate(1, 1.0, 0.1);
+        near.position = Some(pt2(10.0, 0.0));
+        far.position = Some(pt2(30.0, 0.0));
+        let tank = TankView {
+            position: Some(pt2(0.0, 0.0)),
+            ..TANK
+        };
+        let fuller = candidate(0, 2.0, 0.1);
+        assert_eq!(LowestFuel.next(&[fuller, far, near], &tank), Some(2));
+        // without positions the first one wins
+        assert_eq!(LowestFuel.next(&[far, near], &TANK), Some(1));
+    }
+
+    #[test]
+    fn lowest_fuel_puts_a_nan_level_last() {
+        let candidates = [candidate(0, f32::NAN, 0.1), candidate(1, 5.0, 0.1)];
+        assert_eq!(LowestFuel.next(&candidates, &TANK), Some(1));
+    }
+
+    #[test]
+    fn earliest_empty_breaks_a_tie_by_index() {
+        let mut candidates = [
+            candidate(3, 2.0, 0.1),
+            candidate(1, 4.0, 0.2),
+            candidate(2, 9.0, 0.1),
+        ];
+        // asked at the same moment, the first two run out together
+        let since = candidates[0].request.since;
+        for c in candidates.iter_mut() {
+            c.request.since = since;
+        }
+        assert_eq!(EarliestEmpty.next(&candidates, &TANK), Some(1));
+    }
+
+    #[test]
+    fn a_station_which_burns_nothing_never_runs_out() {
+        let idle = candidate(0, 0.5, 0.0);
+        assert_eq!(idle.request.time_to_empty(), f32::INFINITY);
+        let burning = candidate(1, 5.0, 0.5);
+        assert!(burning.request.time_to_empty() <= 10.0);
+        assert!(burning.request.time_to_empty() > 9.0);
+        assert_eq!(EarliestEmpty.next(&[idle, burning], &TANK), Some(1));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::dispatch::{Candidate, DispatchStrategy, Positions, StationRequest, TankView};
+use crate::posixmq::{self, Msg, PMQ};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// Station requests shared by the fleet, a station is claimed by one tank at a time.
+/// The long-haul tanks have a coordinator of their own for the depot requests.
+#[derive(Default)]
+pub struct Coordinator {
+    requests: HashMap<usize, StationRequest>,
+    /// Station index to the tank which goes there
+    claims: HashMap<usize, usize>,
+    /// Mines out of ore, the news a full tank queue dropped stays here
+    depleted: HashSet<usize>,
+}
+
+impl Coordinator {
+    /// Collect the requests and mine news from the `queue`
+    /// and pass them on to each of the `tanks`
+    pub fn spawn(queue: &'static str, tanks: Range<usize>) -> Arc<Mutex<Self>> {
+        let coordinator = Arc::new(Mutex::new(Coordinator::default()));
+        let shared = coordinator.clone();
+        thread::spawn(move || {
+            info!(
+                "Employ fleet coordinator on {} for tanks {:?}",
+                queue, tanks
+            );
+            let q = PMQ::open(queue);
+            // a full or unread tank queue must not hold up the others
+            let mq_v: Vec<(usize, PMQ)> = tanks
+                .map(|tank| {
+                    let q = PMQ::open(posixmq::vehicle_queue(tank).as_ref()).nonblocking();
+                    (tank, q)
+                })
+                .collect();
+            loop {
+                let msg = match q.receive() {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!("Dispatch queue receive error: {:?}", e);
+                        break;
+                    }
+                };
+                trace!("Coordinator receive msg: {:?}", msg);
+                match msg {
+                    Msg::IdleStation(idx, level, rate) => {
+                        shared.lock().unwrap().requests.insert(
+                            idx,
+                            StationRequest {
+                                level,
+                                rate,
+                                since: Instant::now(),
+                            },
+                        );
+                    }
+                    Msg::MineDepleted(idx) => {
+                        shared.lock().unwrap().depleted.insert(idx);
+                    }
+                    msg => {
+                        error!("Unsupported message for coordinator: {:?}", msg);
+                        continue;
+                    }
+                }
+                // idle tanks pick the new request up, all of them learn about the mine;
+                // a dropped message stays in the requests or the depleted mines
+                for (tank, q) in mq_v.iter() {
+                    if let Err(e) = q.send(msg.clone()) {
+                        warn!("Drop {:?} for tank #{}: {}", msg, tank, e);
+                    }
+                }
+            }
+        });
+        coordinator
+    }
+
+    /// Release the claims of the `tank` and claim up to `stops` stations
+    /// the strategy picks one by one among the ones no other tank goes to
+    pub fn route(
+        &mut self,
+        tank: usize,
+        stops: usize,
+        strategy: &mut dyn DispatchStrategy,
+        positions: &Positions,
+        view: TankView,
+    ) -> Vec<usize> {
+        self.release(tank);
+        let claims = &self.claims;
+        let mut candidates: Vec<Candidate> = self
+            .requests
+            .iter()
+            .filter(|(idx, _)| !claims.contains_key(idx))
+            .map(|(idx, request)| Candidate {
+                idx: *idx,
+                request: *request,
+                position: positions.stations.get(*idx).cloned(),
+            })
+            .collect();
+        candidates.sort_by_key(|c| c.idx);
+        let mut route = Vec::new();
+        while route.len() < stops {
+            match strategy.next(&candidates, &view) {
+                Some(idx) => {
+                    self.claims.insert(idx, tank);
+                    candidates.retain(|c| c.idx != idx);
+                    route.push(idx);
+                }
+                None => break,
+            }
+        }
+        trace!(
+            "{} chose {:?} for the tank #{} {:?} with {:.1} of {} fuel, claims {:?}",
+            strategy.name(),
+            route,
+            tank,
+            view.state,
+            view.fuel,
+            view.capacity,
+            self.claims
+        );
+        route
+    }
+
+    /// The tank goes to the station picked by hand, whoever claimed it before
+    pub fn claim(&mut self, tank: usize, idx: usize) {
+        self.release(tank);
+        if let Some(other) = self.claims.insert(idx, tank) {
+            if other != tank {
+                warn!(
+                    "Station #{} claimed by tank #{} is taken by #{}",
+                    idx, other, tank
+                );
+            }
+        }
+    }
+
+    /// The station got its fuel or can't take it, forget the request
+    pub fn remove(&mut self, idx: usize) {
+        let request = self.requests.remove(&idx);
+        self.claims.remove(&idx);
+        info!("Remove station #{} request {:?}", idx, request);
+    }
+
+    /// Mines which ran out of ore
+    pub fn depleted(&self) -> &HashSet<usize> {
+        &self.depleted
+    }
+
+    pub fn release(&mut self, tank: usize) {
+        self.claims.retain(|_, t| *t != tank);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::demand::DemandProfile;
+use nannou::ui::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Supply shortfall below this is not a blackout
+const TOLERANCE: f32 = 1e-3;
+
+/// Power grid fed by the stations against the total demand
+pub struct Grid {
+    id: widget::Id,
+    id_stats: widget::Id,
+    demand: DemandProfile,
+    start: Instant,
+    /// Start of the current blackout
+    blackout: Option<Instant>,
+    blackouts: u32,
+    /// Duration of the finished blackouts
+    blackout_time: Duration,
+    label: String,
+    stats: String,
+}
+
+impl Grid {
+    pub fn new(id: widget::Id, id_stats: widget::Id, demand: DemandProfile) -> Self {
+        Grid {
+            id,
+            id_stats,
+            demand,
+            start: Instant::now(),
+            blackout: None,
+            blackouts: 0,
+            blackout_time: Duration::from_secs(0),
+            label: String::new(),
+            stats: String::new(),
+        }
+    }
+
+    /// Total duration of the blackouts including the current one
+    pub fn blackout_time(&self) -> Duration {
+        self.blackout_time
+            + self
+                .blackout
+                .map_or(Duration::from_secs(0), |b| b.elapsed())
+    }
+
+    /// Compare the `supplied` power with the demand and show both under `parent`
+    pub fn update(&mut self, ui: &mut UiCell, parent: widget::Id, supplied: f32) {
+        let t = self.start.elapsed();
+        let demanded = self
+            .demand
+            .at(t.as_secs() as f32 + t.subsec_millis() as f32 / 1e3);
+        let short = supplied + TOLERANCE < demanded;
+        match (short, self.blackout) {
+            (true, None) => {
+                warn!("Blackout: supplied {:.2} of {:.2} MW", supplied, demanded);
+                self.blackout = Some(Instant::now());
+                self.blackouts += 1;
+            }
+            (false, Some(since)) => {
+                info!("Blackout is over after {:.1?}", since.elapsed());
+                self.blackout_time += since.elapsed();
+                self.blackout = None;
+            }
+            _ => (),
+        }
+
+        self.label = format!("{:.2} / {:.2} MW", supplied, demanded);
+        let total = self.blackout_time();
+        self.stats = format!(
+            "blackouts: {}, {}.{}s",
+            self.blackouts,
+            total.as_secs(),
+            total.subsec_millis() / 100
+        );
+        let color = if short {
+            color::rgb(0.9, 0.2, 0.2)
+        } else {
+            color::rgb(0.3, 0.8, 0.3)
+        };
+        widget::Text::new(&self.label)
+            .font_size(15)
+            .color(color)
+            .down_from(parent, 10.0)
+            .set(self.id, ui);
+        widget::Text::new(&self.stats)
+            .font_size(12)
+            .rgb(0.8, 0.8, 0.8)
+            .down_from(self.id, 5.0)
+            .set(self.id_stats, ui);
+    }
+}
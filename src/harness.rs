@@ -0,0 +1,352 @@
+//! The mine, station and tank workers run in a forked test process without
+//! the window, a simulated vehicle arrives wherever its tank heads at once.
+
+use crate::fleet::Coordinator;
+use crate::ledger;
+use crate::map::Node;
+use crate::mine::{Mine, MineStore};
+use crate::posixmq::{self, Msg, DISPATCH_QUEUE, PMQ, STATUS_QUEUE};
+use crate::protocol::{self, Checker};
+use crate::scenario::Scenario;
+use crate::station::{Station, StationStatus};
+use crate::tank::{Control, Role, Tank, TankState};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{self, fork, getpid, pipe, ForkResult};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::mem::{self, Discriminant};
+use std::os::unix::io::FromRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::{env, process, thread};
+
+/// The queue names are fixed, the runs take turns
+static SERIAL: Mutex<()> = Mutex::new(());
+const TICK: Duration = Duration::from_millis(10);
+
+/// Run the `test` of the `scenario` in a forked process: the workers never
+/// stop, their queues go away with the process. Panics if the test fails,
+/// the books don't add up or the messages break the protocol.
+pub fn run(scenario: Scenario, test: fn(&mut Harness)) {
+    let _turn: MutexGuard<()> = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let (read, write) = pipe().expect("Create harness pipe");
+    match fork().expect("Fork harness") {
+        ForkResult::Child => {
+            let _ = unistd::close(read);
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut harness = Harness::new(scenario);
+                test(&mut harness);
+                harness.check_protocol();
+            }));
+            let code = match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    let msg = match e.downcast_ref::<String>() {
+                        Some(msg) => msg.clone(),
+                        None => e.downcast_ref::<&str>().map_or("", |m| m).to_string(),
+                    };
+                    let _ = unistd::write(write, msg.as_bytes());
+                    1
+                }
+            };
+            process::exit(code);
+        }
+        ForkResult::Parent { child } => {
+            let _ = unistd::close(write);
+            let mut msg = String::new();
+            let _ = unsafe { File::from_raw_fd(read) }.read_to_string(&mut msg);
+            match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, 0)) => (),
+                status => panic!("{} ({:?})", msg, status),
+            }
+        }
+    }
+}
+
+/// Vehicle which needs no time to drive
+struct InstantVehicle {
+    tank: Tank,
+    /// The state in which the vehicle arrived, it waits for the tank to take it over
+    arrived: Option<Discriminant<TankState>>,
+    /// Every state the tank went through, as first seen
+    states: Vec<TankState>,
+}
+
+impl InstantVehicle {
+    fn update(&mut self) {
+        let state = self.tank.get_state();
+        if self.states.last().map(mem::discriminant) != Some(mem::discriminant(&state)) {
+            self.states.push(state);
+        }
+        if let TankState::Load(_) | TankState::Unload(_) = state {
+            self.arrived = None;
+            return;
+        }
+        if self.arrived == Some(mem::discriminant(&state)) {
+            return;
+        }
+        self.arrived = Some(mem::discriminant(&state));
+        match state {
+            TankState::Refill(_) => self.tank.load(),
+            TankState::Supply(_) => self.tank.unload(),
+            _ => (),
+        }
+    }
+}
+
+pub struct Harness {
+    vehicles: Vec<InstantVehicle>,
+    stations: Vec<StationStatus>,
+    /// Status messages received from each station
+    reports: Vec<usize>,
+    status: PMQ,
+    trace: PathBuf,
+}
+
+impl Harness {
+    /// Start the mines, the stations and the direct tanks of the `scenario`
+    fn new(scenario: Scenario) -> Self {
+        posixmq::cleanup_posix_queues(&scenario);
+        ledger::start();
+        let trace = env::temp_dir().join(format!("rosdraw-harness-{}.trace", getpid()));
+        protocol::start_trace(trace.to_str().expect("Trace path"), Vec::new());
+        let status = PMQ::open(STATUS_QUEUE).nonblocking();
+        let reports = vec![0; scenario.stations.len()];
+        let stations = scenario
+            .stations
+            .iter()
+            .enumerate()
+            .map(|(idx, config)| {
+                Station::spawn(idx, config, false);
+                StationStatus {
+                    fuel: config.fuel,
+                    ..StationStatus::default()
+                }
+            })
+            .collect();
+        // the forked producer stores into shared memory, no pipe to leave behind
+        let mines: Vec<MineStore> = scenario
+            .mines
+            .iter()
+            .enumerate()
+            .map(|(idx, config)| Mine::spawn(idx, config, true, DISPATCH_QUEUE))
+            .collect();
+        let tanks = scenario.fleet.tanks;
+        let coordinator = Coordinator::spawn(DISPATCH_QUEUE, 0..tanks);
+        let vehicles = (0..tanks)
+            .map(|idx| InstantVehicle {
+                tank: Tank::new(
+                    idx,
+                    Role::Direct,
+                    Control::Auto,
+                    scenario.fleet.stops,
+                    mines.clone(),
+                    scenario.stations.len(),
+                    coordinator.clone(),
+                ),
+                // the new tank is sent to load already
+                arrived: Some(mem::discriminant(&TankState::Refill(0.0))),
+                states: Vec::new(),
+            })
+            .collect();
+        Harness {
+            vehicles,
+            stations,
+            reports,
+            status,
+            trace,
+        }
+    }
+
+    /// Read the station reports and move the vehicles, the fuel books must add up
+    fn tick(&mut self) {
+        while let Ok(msg) = self.status.receive() {
+            if let Msg::StationStatus(idx, fuel, rate, pid, broken) = msg {
+                self.stations[idx] = StationStatus {
+                    fuel,
+                    rate,
+                    pid,
+                    broken,
+                };
+                self.reports[idx] += 1;
+            }
+        }
+        for vehicle in self.vehicles.iter_mut() {
+            vehicle.update();
+        }
+        if let Err(e) = ledger::check() {
+            panic!("{}", e);
+        }
+    }
+
+    /// Run until `done`, `false` if it takes longer than `timeout`
+    pub fn run_until(&mut self, timeout: Duration, done: impl Fn(&Harness) -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            self.tick();
+            if done(self) {
+                return true;
+            }
+            thread::sleep(TICK);
+        }
+        false
+    }
+
+    pub fn station(&self, idx: usize) -> StationStatus {
+        self.stations[idx]
+    }
+
+    pub fn reports(&self, idx: usize) -> usize {
+        self.reports[idx]
+    }
+
+    /// Break or repair the station `idx`
+    pub fn toggle_broken(&self, idx: usize) {
+        PMQ::open(&Node::Station(idx).queue())
+            .send(Msg::ToggleBroken)
+            .expect("Send toggle broken");
+    }
+
+    /// States of the tank `idx` so far, a state shows up again after another one
+    pub fn states(&self, idx: usize) -> &[TankState] {
+        &self.vehicles[idx].states
+    }
+
+    /// The messages so far as `<queue> <message>` lines
+    pub fn trace(&self) -> Vec<String> {
+        fs::read_to_string(&self.trace)
+            .expect("Read harness trace")
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn check_protocol(&self) {
+        let trace = File::open(&self.trace).expect("Open harness trace");
+        let report = Checker::check(BufReader::new(trace)).expect("Check harness trace");
+        assert!(report.passed(), "{}", report);
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.trace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::{MineConfig, StationConfig};
+
+    const TIMEOUT: Duration = Duration::from_secs(20);
+
+    /// A fast mine and the `station`
+    fn scenario(station: StationConfig) -> Scenario {
+        Scenario {
+            mines: vec![MineConfig {
+                production: 7.0,
+                ..MineConfig::default()
+            }],
+            stations: vec![station],
+            ..Scenario::default()
+        }
+    }
+
+    /// The tank left the station and has nowhere else to go
+    fn back_to_refill(h: &Harness) -> bool {
+        h.states(0)
+            .windows(2)
+            .any(|w| matches!(w, [TankState::Unload(_), TankState::Refill(_)]))
+    }
+
+    #[test]
+    fn a_station_at_zero_gets_refilled() {
+        let station = StationConfig {
+            fuel: 2.0,
+            burn: 0.5,
+            low: 0.0,
+            ..StationConfig::default()
+        };
+        run(scenario(station), |h| {
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).fuel <= 0.0));
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).fuel > 0.0));
+            assert!(h
+                .states(0)
+                .iter()
+                .any(|s| matches!(s, TankState::Unload(_))));
+        });
+    }
+
+    #[test]
+    fn overflow_goes_back_to_the_tank() {
+        let station = StationConfig {
+            fuel: 0.0,
+            burn: 0.0,
+            low: 2.0,
+            high: 3.0,
+            ..StationConfig::default()
+        };
+        run(scenario(station), |h| {
+            assert!(h.run_until(TIMEOUT, back_to_refill));
+            // the station keeps 3 of the portion of 5 and returns the rest
+            let trace = h.trace();
+            let delivery = trace.iter().position(|m| m == "/mq-s0 delivery 0 5");
+            let delivery = delivery.expect("Deliver a portion");
+            assert!(trace[delivery..].iter().any(|m| m == "/mq-v0 fuel 2"));
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).fuel == 3.0));
+        });
+    }
+
+    #[test]
+    fn the_tank_refills_when_no_station_is_idle() {
+        let station = StationConfig {
+            fuel: 0.0,
+            burn: 0.0,
+            low: 2.0,
+            high: 20.0,
+            ..StationConfig::default()
+        };
+        run(scenario(station), |h| {
+            assert!(h.run_until(TIMEOUT, back_to_refill));
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).fuel > 2.0));
+            // loaded again, and no station to go to
+            assert!(h.run_until(TIMEOUT, |h| matches!(
+                h.states(0).last(),
+                Some(TankState::Load(_))
+            )));
+            assert!(!h.run_until(Duration::from_secs(1), |h| matches!(
+                h.states(0).last(),
+                Some(TankState::Supply(_))
+            )));
+        });
+    }
+
+    #[test]
+    fn a_station_broken_during_a_delivery_keeps_running() {
+        // more than a tank load below the low level
+        let station = StationConfig {
+            fuel: 0.0,
+            burn: 0.0,
+            low: 40.0,
+            high: 50.0,
+            ..StationConfig::default()
+        };
+        run(scenario(station), |h| {
+            assert!(h.run_until(TIMEOUT, |h| matches!(
+                h.states(0).last(),
+                Some(TankState::Unload(_))
+            )));
+            h.toggle_broken(0);
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).broken));
+            let reports = h.reports(0);
+            assert!(h.run_until(TIMEOUT, |h| h.reports(0) > reports + 5));
+            // repaired, it asks for more
+            let fuel = h.station(0).fuel;
+            h.toggle_broken(0);
+            assert!(h.run_until(TIMEOUT, |h| h.station(0).fuel > fuel));
+        });
+    }
+}
@@ -0,0 +1,94 @@
+use crate::shm::ShmCell;
+use std::sync::OnceLock;
+
+const LEDGER_SHM: &str = "/rosdraw-ledger";
+/// Float rounding the books let pass, a share of the fuel produced
+const TOLERANCE: f64 = 1e-4;
+const MIN_TOLERANCE: f64 = 0.01;
+
+/// Fuel totals of the whole simulation, kept in shared memory so that the
+/// forked stations and mine producers book their fuel as well
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Totals {
+    /// Mined fuel and the opening stock of the stations and depots
+    pub produced: f64,
+    /// Burned by the stations and the vehicles
+    pub burned: f64,
+    /// Held by the mines, depots, stations and tanks
+    pub stock: f64,
+    /// Sent in the queues and not received yet
+    pub transit: f64,
+}
+
+impl Totals {
+    /// Fuel missing from the books, negative if some appeared from nowhere
+    pub fn drift(&self) -> f64 {
+        self.produced - self.burned - self.stock - self.transit
+    }
+
+    pub fn is_balanced(&self) -> bool {
+        self.drift().abs() <= f64::max(MIN_TOLERANCE, self.produced * TOLERANCE)
+    }
+}
+
+static LEDGER: OnceLock<ShmCell<Totals>> = OnceLock::new();
+
+/// Open the books, before the stations and the mines fork
+pub fn start() {
+    if LEDGER.get().is_some() {
+        warn!("The fuel ledger is already open");
+        return;
+    }
+    let _ = LEDGER.set(ShmCell::create(LEDGER_SHM, Totals::default()));
+}
+
+fn book(entry: impl FnOnce(&mut Totals)) {
+    if let Some(ledger) = LEDGER.get() {
+        entry(&mut ledger.lock());
+    }
+}
+
+/// New fuel in a stock
+pub fn produce(amount: f32) {
+    book(|t| {
+        t.produced += amount as f64;
+        t.stock += amount as f64;
+    });
+}
+
+/// Fuel gone from a stock
+pub fn burn(amount: f32) {
+    book(|t| {
+        t.burned += amount as f64;
+        t.stock -= amount as f64;
+    });
+}
+
+/// A holder sent or received fuel: its `stock` changed by the fuel it really
+/// keeps and the `transit` by the amounts of the messages
+pub fn transfer(stock: f32, transit: f32) {
+    book(|t| {
+        t.stock += stock as f64;
+        t.transit += transit as f64;
+    });
+}
+
+pub fn totals() -> Option<Totals> {
+    LEDGER.get().map(|ledger| *ledger.lock())
+}
+
+/// Whether the books add up: produced = burned + in stock + in transit
+pub fn check() -> Result<(), String> {
+    match totals() {
+        Some(t) if !t.is_balanced() => Err(format!(
+            "fuel ledger is off by {:.3}: produced {:.1}, burned {:.1}, stock {:.1}, transit {:.1}",
+            t.drift(),
+            t.produced,
+            t.burned,
+            t.stock,
+            t.transit
+        )),
+        _ => Ok(()),
+    }
+}
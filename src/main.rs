@@ -4,17 +4,32 @@ extern crate log;
 extern crate conrod;
 
 use env_logger;
+use map::WORLD_WIDTH;
 use model::{model, Model};
 use nannou;
-use nannou::event::SimpleWindowEvent;
+use nannou::event::{MouseButton, SimpleWindowEvent};
 use nannou::prelude::*;
 use std::env;
 
+mod demand;
+mod depot;
+mod dispatch;
+mod fleet;
+mod grid;
+#[cfg(test)]
+mod harness;
+mod ledger;
+mod map;
 mod mine;
 mod model;
+mod msg;
 mod posixmq;
+mod protocol;
+mod scenario;
+mod shm;
 mod station;
 mod tank;
+mod tank_machine;
 mod vehicle;
 
 fn main() {
@@ -25,28 +40,66 @@ fn main() {
     );
     env_logger::init();
 
-    posixmq::cleanup_posix_queues();
-
+    // grade a recorded trace instead of running the simulation
+    if let Some(path) = env::args().find_map(|a| a.strip_prefix("--check=").map(String::from)) {
+        std::process::exit(protocol::check_file(&path));
+    }
     nannou::app(model, event, view).run();
 }
 
-fn event(_app: &App, mut m: Model, event: Event) -> Model {
+fn event(app: &App, mut m: Model, event: Event) -> Model {
     match event {
         Event::Update(_update) => {
+            let key = |k| if app.keys.down.contains(&k) { 1.0 } else { 0.0 };
+            m.steer(vec2(
+                key(Key::Right) - key(Key::Left),
+                key(Key::Up) - key(Key::Down),
+            ));
             m.update();
         }
         Event::WindowEvent {
-            simple: Some(SimpleWindowEvent::Resized(_)),
+            simple: Some(SimpleWindowEvent::KeyPressed(nannou::VirtualKeyCode::Space)),
             ..
         } => {
-            m.vehicle.resize();
+            m.toggle_freeze();
         }
 
         Event::WindowEvent {
-            simple: Some(SimpleWindowEvent::KeyPressed(nannou::VirtualKeyCode::Space)),
+            simple: Some(SimpleWindowEvent::KeyPressed(nannou::VirtualKeyCode::P)),
             ..
         } => {
-            m.toggle_freeze();
+            m.resume_profiles();
+        }
+
+        Event::WindowEvent {
+            simple: Some(SimpleWindowEvent::KeyPressed(nannou::VirtualKeyCode::L)),
+            ..
+        } => {
+            m.player_load();
+        }
+
+        Event::WindowEvent {
+            simple: Some(SimpleWindowEvent::KeyPressed(nannou::VirtualKeyCode::U)),
+            ..
+        } => {
+            m.player_unload();
+        }
+
+        Event::WindowEvent {
+            simple: Some(SimpleWindowEvent::MousePressed(MouseButton::Right)),
+            ..
+        } => {
+            m.toggle_broken_at(pt2(app.mouse.x, app.mouse.y));
+        }
+
+        Event::WindowEvent {
+            simple: Some(SimpleWindowEvent::MousePressed(MouseButton::Left)),
+            ..
+        } => {
+            let point = pt2(app.mouse.x, app.mouse.y);
+            if !m.dispatch_at(point) {
+                m.toggle_road_at(point);
+            }
         }
 
         _ => (),
@@ -58,7 +111,18 @@ fn view(app: &App, model: &Model, frame: Frame) -> Frame {
     let draw = app.draw();
     draw.background().rgb(0.02, 0.02, 0.02);
 
-    model.vehicle.draw(&draw);
+    model
+        .map
+        .lock()
+        .unwrap()
+        .draw(&draw, app.window_rect().w() / WORLD_WIDTH);
+
+    for mine in model.mines.iter() {
+        mine.draw(&draw);
+    }
+    for vehicle in model.vehicles.iter() {
+        vehicle.draw(&draw);
+    }
 
     draw.to_frame(app, &frame).unwrap();
     model.ui.draw_to_frame(app, &frame).unwrap();
@@ -0,0 +1,435 @@
+use crate::posixmq::{DEPOT_QUEUE_PREFIX, MINE_QUEUE_PREFIX, STATION_QUEUE_PREFIX};
+use crate::scenario::Scenario;
+use nannou::prelude::*;
+use nannou::Draw;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// World units (km) across the window width
+pub const WORLD_WIDTH: f32 = 100.0;
+
+// default layout: mines along the bottom left, stations along the top right
+const MINE_ORIGIN: (f32, f32) = (-37.0, -30.0);
+const MINE_STEP: f32 = 23.0;
+const STATION_ORIGIN: (f32, f32) = (44.0, 13.0);
+const STATION_STEP: f32 = -14.0;
+const DEPOT_ORIGIN: (f32, f32) = (12.0, -30.0);
+const DEPOT_STEP: f32 = 16.0;
+
+/// A place on the map, in the scenario `m0` is the first mine,
+/// `s0` the first station and `d0` the first depot
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Node {
+    Mine(usize),
+    Station(usize),
+    Depot(usize),
+}
+
+impl Node {
+    /// The queue the node receives the tank messages on
+    pub fn queue(self) -> String {
+        match self {
+            Node::Mine(idx) => format!("{}{}", MINE_QUEUE_PREFIX, idx),
+            Node::Station(idx) => format!("{}{}", STATION_QUEUE_PREFIX, idx),
+            Node::Depot(idx) => format!("{}{}", DEPOT_QUEUE_PREFIX, idx),
+        }
+    }
+}
+
+impl FromStr for Node {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let idx = s
+            .get(1..)
+            .and_then(|i| i.parse().ok())
+            .ok_or_else(|| format!("expected a node like m0 or s1, got '{}'", s))?;
+        match s.chars().next() {
+            Some('m') => Ok(Node::Mine(idx)),
+            Some('s') => Ok(Node::Station(idx)),
+            Some('d') => Ok(Node::Depot(idx)),
+            _ => Err(format!("unknown node '{}'", s)),
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Mine(idx) => write!(f, "m{}", idx),
+            Node::Station(idx) => write!(f, "s{}", idx),
+            Node::Depot(idx) => write!(f, "d{}", idx),
+        }
+    }
+}
+
+struct Road {
+    from: Node,
+    to: Node,
+    via: Vec<Point2>,
+    /// Cost for the route search
+    length: f32,
+    capacity: Option<usize>,
+    /// Tanks on the road now
+    vehicles: usize,
+    open: bool,
+}
+
+impl Road {
+    fn new(from: Node, to: Node, via: Vec<Point2>) -> Self {
+        Road {
+            from,
+            to,
+            via,
+            length: 0.0,
+            capacity: None,
+            vehicles: 0,
+            open: true,
+        }
+    }
+
+    fn is_passable(&self) -> bool {
+        self.open
+            && match self.capacity {
+                Some(c) => self.vehicles < c,
+                None => true,
+            }
+    }
+}
+
+/// A road of a route, driven towards the `to` node
+#[derive(Clone, Debug)]
+pub struct Leg {
+    pub road: usize,
+    pub to: Node,
+    /// World points of the road including both ends
+    pub points: Vec<Point2>,
+}
+
+/// Node positions and the roads between them, in world units
+pub struct Map {
+    pub mines: Vec<Point2>,
+    pub stations: Vec<Point2>,
+    pub depots: Vec<Point2>,
+    roads: Vec<Road>,
+    /// Bumped when a road opens or closes, the planned routes are stale then
+    pub version: u64,
+}
+
+impl Map {
+    /// Without roads in the scenario every mine has a straight road to every station,
+    /// or with depots every mine to every depot and every depot to every station
+    pub fn new(scenario: &Scenario) -> Self {
+        let place = |origin: (f32, f32), step: f32, idx: usize, x: Option<f32>, y: Option<f32>| {
+            pt2(
+                x.unwrap_or(origin.0 + step * idx as f32),
+                y.unwrap_or(origin.1),
+            )
+        };
+        let mines: Vec<Point2> = scenario
+            .mines
+            .iter()
+            .enumerate()
+            .map(|(idx, m)| place(MINE_ORIGIN, MINE_STEP, idx, m.x, m.y))
+            .collect();
+        let stations: Vec<Point2> = scenario
+            .stations
+            .iter()
+            .enumerate()
+            .map(|(idx, s)| place(STATION_ORIGIN, STATION_STEP, idx, s.x, s.y))
+            .collect();
+        let depots: Vec<Point2> = scenario
+            .depots
+            .iter()
+            .enumerate()
+            .map(|(idx, d)| place(DEPOT_ORIGIN, DEPOT_STEP, idx, d.x, d.y))
+            .collect();
+        let roads = if !scenario.roads.is_empty() {
+            scenario
+                .roads
+                .iter()
+                .map(|r| {
+                    let mut road = Road::new(
+                        r.from,
+                        r.to,
+                        r.via.iter().map(|&(x, y)| pt2(x, y)).collect(),
+                    );
+                    road.length = r.length.unwrap_or(0.0);
+                    road.capacity = r.capacity;
+                    road
+                })
+                .collect()
+        } else if depots.is_empty() {
+            Self::connect(mines.len(), stations.len(), Node::Mine, Node::Station)
+        } else {
+            let mut roads = Self::connect(mines.len(), depots.len(), Node::Mine, Node::Depot);
+            roads.extend(Self::connect(
+                depots.len(),
+                stations.len(),
+                Node::Depot,
+                Node::Station,
+            ));
+            roads
+        };
+        let mut map = Map {
+            mines,
+            stations,
+            depots,
+            roads,
+            version: 0,
+        };
+        for idx in 0..map.roads.len() {
+            if map.roads[idx].length <= 0.0 {
+                let points = map.points(idx);
+                map.roads[idx].length = points.windows(2).map(|l| l[0].distance(l[1])).sum();
+            }
+        }
+        map
+    }
+
+    /// Straight roads from each of the `from` nodes to each of the `to` nodes
+    fn connect(from: usize, to: usize, a: fn(usize) -> Node, b: fn(usize) -> Node) -> Vec<Road> {
+        (0..from)
+            .flat_map(|i| (0..to).map(move |j| Road::new(a(i), b(j), Vec::new())))
+            .collect()
+    }
+
+    /// Whether the `node` is on the map
+    pub fn contains(&self, node: Node) -> bool {
+        match node {
+            Node::Mine(idx) => idx < self.mines.len(),
+            Node::Station(idx) => idx < self.stations.len(),
+            Node::Depot(idx) => idx < self.depots.len(),
+        }
+    }
+
+    pub fn position(&self, node: Node) -> Point2 {
+        match node {
+            Node::Mine(idx) => self.mines[idx],
+            Node::Station(idx) => self.stations[idx],
+            Node::Depot(idx) => self.depots[idx],
+        }
+    }
+
+    /// Points of the road including both ends, from its `from` node
+    fn points(&self, road: usize) -> Vec<Point2> {
+        let road = &self.roads[road];
+        let mut points = vec![self.position(road.from)];
+        points.extend(road.via.iter().cloned());
+        points.push(self.position(road.to));
+        points
+    }
+
+    /// The shortest way over the open roads with room for one more tank,
+    /// `None` if `to` can't be reached
+    pub fn route(&self, from: Node, to: Node) -> Option<Vec<Leg>> {
+        let mut distance: HashMap<Node, f32> = HashMap::new();
+        // the road which leads to the node on the shortest way
+        let mut previous: HashMap<Node, usize> = HashMap::new();
+        let mut done: HashSet<Node> = HashSet::new();
+        distance.insert(from, 0.0);
+        loop {
+            let (node, d) = distance
+                .iter()
+                .filter(|(n, _)| !done.contains(n))
+                .min_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(n, d)| (*n, *d))?;
+            if node == to {
+                break;
+            }
+            done.insert(node);
+            for (idx, road) in self.roads.iter().enumerate() {
+                let next = match (road.from == node, road.to == node) {
+                    (true, _) => road.to,
+                    (_, true) => road.from,
+                    _ => continue,
+                };
+                if !road.is_passable() || done.contains(&next) {
+                    continue;
+                }
+                let d = d + road.length;
+                let shorter = match distance.get(&next) {
+                    Some(old) => d < *old,
+                    None => true,
+                };
+                if shorter {
+                    distance.insert(next, d);
+                    previous.insert(next, idx);
+                }
+            }
+        }
+        let mut legs = Vec::new();
+        let mut node = to;
+        while node != from {
+            let idx = previous[&node];
+            let road = &self.roads[idx];
+            let mut points = self.points(idx);
+            let back = if road.to == node {
+                road.from
+            } else {
+                points.reverse();
+                road.to
+            };
+            legs.push(Leg {
+                road: idx,
+                to: node,
+                points,
+            });
+            node = back;
+        }
+        legs.reverse();
+        Some(legs)
+    }
+
+    /// Take a place on the road, `false` if it is closed or full
+    pub fn enter(&mut self, road: usize) -> bool {
+        let road = &mut self.roads[road];
+        if !road.is_passable() {
+            return false;
+        }
+        road.vehicles += 1;
+        true
+    }
+
+    pub fn leave(&mut self, road: usize) {
+        let road = &mut self.roads[road];
+        road.vehicles = road.vehicles.saturating_sub(1);
+    }
+
+    /// Close or open the road passing within `tolerance` of the world `point`
+    pub fn toggle_road_at(&mut self, point: Point2, tolerance: f32) {
+        let near = (0..self.roads.len()).find(|idx| {
+            self.points(*idx)
+                .windows(2)
+                .any(|l| segment_distance(point, l[0], l[1]) <= tolerance)
+        });
+        if let Some(idx) = near {
+            let road = &mut self.roads[idx];
+            road.open = !road.open;
+            self.version += 1;
+            info!(
+                "Road {}-{} is {}",
+                road.from,
+                road.to,
+                if road.open { "open" } else { "closed" }
+            );
+        }
+    }
+
+    /// Open roads are grey, closed ones red
+    pub fn draw(&self, draw: &Draw, scale: f32) {
+        for (idx, road) in self.roads.iter().enumerate() {
+            let (r, g, b) = if road.open {
+                (0.25, 0.25, 0.25)
+            } else {
+                (0.5, 0.1, 0.1)
+            };
+            for leg in self.points(idx).windows(2) {
+                draw.line()
+                    .start(leg[0] * scale)
+                    .end(leg[1] * scale)
+                    .thickness(3.0)
+                    .rgb(r, g, b);
+            }
+        }
+    }
+}
+
+fn segment_distance(p: Point2, a: Point2, b: Point2) -> f32 {
+    let ab = b - a;
+    let len = ab.x * ab.x + ab.y * ab.y;
+    if len <= 0.0 {
+        return p.distance(a);
+    }
+    let t = ((p - a).x * ab.x + (p - a).y * ab.y) / len;
+    p.distance(a + ab * t.clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two roads from the mine to the station, the second one is shorter
+    const TWO_ROADS: &str = "
+mine x=0 y=0
+station x=20 y=0
+road from=m0 to=s0 via=10:20
+road from=m0 to=s0 via=10:-1 capacity=1
+";
+
+    fn map(text: &str) -> Map {
+        Map::new(&Scenario::parse(text).unwrap())
+    }
+
+    fn roads(legs: Option<Vec<Leg>>) -> Option<Vec<usize>> {
+        legs.map(|legs| legs.iter().map(|l| l.road).collect())
+    }
+
+    #[test]
+    fn the_route_takes_the_shorter_road() {
+        let map = map(TWO_ROADS);
+        let legs = map.route(Node::Mine(0), Node::Station(0)).unwrap();
+        assert_eq!(legs.len(), 1);
+        assert_eq!(legs[0].road, 1);
+        assert_eq!(legs[0].points.last(), Some(&pt2(20.0, 0.0)));
+        // and the same road back
+        let back = map.route(Node::Station(0), Node::Mine(0));
+        assert_eq!(roads(back), Some(vec![1]));
+    }
+
+    #[test]
+    fn only_the_nodes_of_the_scenario_are_on_the_map() {
+        let map = map(TWO_ROADS);
+        assert!(map.contains(Node::Mine(0)));
+        assert!(map.contains(Node::Station(0)));
+        assert!(!map.contains(Node::Station(1)));
+        assert!(!map.contains(Node::Depot(0)));
+    }
+
+    #[test]
+    fn a_closed_road_forces_a_detour() {
+        let mut map = map(TWO_ROADS);
+        map.toggle_road_at(pt2(10.0, -1.0), 0.5);
+        assert_eq!(map.version, 1);
+        assert_eq!(
+            roads(map.route(Node::Mine(0), Node::Station(0))),
+            Some(vec![0])
+        );
+        map.toggle_road_at(pt2(10.0, 20.0), 0.5);
+        assert!(map.route(Node::Mine(0), Node::Station(0)).is_none());
+    }
+
+    #[test]
+    fn a_full_road_is_not_passable() {
+        let mut map = map(TWO_ROADS);
+        assert!(map.enter(1));
+        assert!(!map.enter(1));
+        assert_eq!(
+            roads(map.route(Node::Mine(0), Node::Station(0))),
+            Some(vec![0])
+        );
+        map.leave(1);
+        assert_eq!(
+            roads(map.route(Node::Mine(0), Node::Station(0))),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn a_road_without_a_real_length_is_rejected() {
+        for bad in &["length=nan", "length=-1", "length=inf", "via=nan:3"] {
+            let text = format!("mine\nstation\nroad from=m0 to=s0 {}", bad);
+            assert!(Scenario::parse(&text).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn a_node_without_a_real_position_is_rejected() {
+        for bad in &["mine x=nan", "station y=inf", "depot x=-inf"] {
+            let text = format!("{}\nfleet haulers=1", bad);
+            assert!(Scenario::parse(&text).is_err(), "{}", bad);
+        }
+        assert!(Scenario::parse("mine x=-5.5 y=3").is_ok());
+    }
+}
@@ -1,78 +1,308 @@
-use crate::posixmq::{Msg, MINE_QUEUE, PMQ, VEHICLE_QUEUE};
+use crate::ledger;
+use crate::posixmq::{self, Msg, MINE_QUEUE_PREFIX, PMQ};
+use crate::scenario::MineConfig;
+use crate::shm::{ShmCell, ShmGuard};
 use bytepack::{LEPacker, LEUnpacker};
+use nannou::prelude::*;
 use nannou::rand::random_f32;
 use nannou::ui::prelude::*;
+use nannou::Draw;
 use nix::sys::stat;
 use nix::unistd::{fork, getpid, getppid, mkfifo, ForkResult};
 use nix::{errno::Errno, Error};
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
+use std::ops::{Deref, DerefMut};
 use std::os::unix::fs::OpenOptionsExt;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use std::{process, thread};
 
-const MINE_PIPE: &'static str = "mine.pipe";
+const MINE_PIPE_PREFIX: &str = "mine";
+const MINE_SHM_PREFIX: &str = "/rosdraw-mine";
+// the stock below this level counts as empty
+const EMPTY_LEVEL: f32 = 0.01;
+// a nearly exhausted reserve still yields this share of the production
+const MIN_FALLOFF: f32 = 0.05;
+const MINE_WIDTH: f64 = 60.0;
+const BAY_SIZE: f32 = 10.0;
+
+/// A fuel request being served, or waiting for a free bay
+#[derive(Copy, Clone, Debug)]
+struct Bay {
+    tank: usize,
+    left: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct MineStock {
+    pub fuel: f32,
+    pub speed: f32,
+    /// Fuel left in the ground, unlimited if `None`
+    pub reserve: Option<f32>,
+    /// Busy loading bays
+    pub loading: u32,
+    /// Requests waiting for a free bay
+    pub waiting: u32,
+}
+
+impl MineStock {
+    fn is_depleted(&self) -> bool {
+        match self.reserve {
+            Some(r) => r <= 0.0 && self.fuel < EMPTY_LEVEL,
+            None => false,
+        }
+    }
+
+    /// Store a produced `portion` scaled by the speed and the reserve falloff
+    fn produce(&mut self, portion: f32, config: &MineConfig) -> f32 {
+        let mut portion = portion * self.speed;
+        if let (Some(total), Some(left)) = (config.reserve, self.reserve) {
+            let share = f32::max((left / total).powf(config.falloff), MIN_FALLOFF);
+            portion = f32::min(portion * share, left);
+        }
+        if portion <= 0.0 || self.fuel >= config.capacity {
+            return 0.0;
+        }
+        let portion = f32::min(portion, config.capacity - self.fuel);
+        self.fuel += portion;
+        ledger::produce(portion);
+        if let Some(left) = self.reserve.as_mut() {
+            *left = f32::max(*left - portion, 0.0);
+        }
+        portion
+    }
+}
+
+/// Where the mine stock lives: behind a mutex in this process, or in a
+/// shared memory segment which the forked producer updates directly
+#[derive(Clone)]
+pub enum MineStore {
+    Local(Arc<Mutex<MineStock>>),
+    Shared(Arc<ShmCell<MineStock>>),
+}
+
+pub enum StockGuard<'a> {
+    Local(MutexGuard<'a, MineStock>),
+    Shared(ShmGuard<'a, MineStock>),
+}
+
+impl<'a> Deref for StockGuard<'a> {
+    type Target = MineStock;
+    fn deref(&self) -> &MineStock {
+        match self {
+            StockGuard::Local(g) => g,
+            StockGuard::Shared(g) => g,
+        }
+    }
+}
+
+impl<'a> DerefMut for StockGuard<'a> {
+    fn deref_mut(&mut self) -> &mut MineStock {
+        match self {
+            StockGuard::Local(g) => g,
+            StockGuard::Shared(g) => g,
+        }
+    }
+}
+
+impl MineStore {
+    fn new(idx: usize, stock: MineStock, shared: bool) -> Self {
+        if shared {
+            let name = format!("{}{}", MINE_SHM_PREFIX, idx);
+            MineStore::Shared(Arc::new(ShmCell::create(&name, stock)))
+        } else {
+            Self::local(stock)
+        }
+    }
+
+    /// Stock behind a mutex, for the fuel kept by a depot
+    pub fn local(stock: MineStock) -> Self {
+        MineStore::Local(Arc::new(Mutex::new(stock)))
+    }
+
+    pub fn lock(&self) -> StockGuard<'_> {
+        match self {
+            MineStore::Local(m) => StockGuard::Local(m.lock().unwrap()),
+            MineStore::Shared(m) => StockGuard::Shared(m.lock()),
+        }
+    }
+
+    pub fn fuel(&self) -> f32 {
+        self.lock().fuel
+    }
+}
 
 pub struct Mine {
     id: widget::Id,
+    id_speed: widget::Id,
+    id_info: widget::Id,
     label: String,
     height: f64,
-    fuel: Arc<Mutex<f32>>,
+    store: MineStore,
     speed_update: f32,
-    speed: Arc<Mutex<f32>>,
     capacity: f32,
-    pub q: PMQ,
+    bays: usize,
+    // bottom right corner of the gauge, the bays are drawn next to it
+    corner: Option<Point2>,
 }
 
 impl Mine {
-    pub fn new(id: widget::Id) -> Self {
-        let capacity = 200.0; // 2x station's capacity
-        let fuel = Arc::new(Mutex::new(0.0));
-        let speed_update = 0.0;
-        let speed = Arc::new(Mutex::new(speed_update));
-        Self::launch(capacity, fuel.clone(), speed.clone());
+    pub fn new(
+        idx: usize,
+        id: widget::Id,
+        id_speed: widget::Id,
+        id_info: widget::Id,
+        config: &MineConfig,
+        shared: bool,
+        dispatch: &'static str,
+    ) -> Self {
+        let store = Self::spawn(idx, config, shared, dispatch);
         Mine {
             id,
+            id_speed,
+            id_info,
             label: "0".to_string(),
             height: 200.0,
-            fuel,
-            speed_update,
-            speed,
-            capacity,
-            q: PMQ::open(MINE_QUEUE),
+            store,
+            speed_update: config.production,
+            capacity: config.capacity,
+            bays: config.bays,
+            corner: None,
         }
     }
 
-    pub fn update_ui(&mut self, ui: &mut UiCell, speed: f32) {
-        let fuel = *self.fuel.lock().unwrap();
-        if self.speed_update != speed {
-            self.speed_update = speed;
-            *self.speed.lock().unwrap() = speed;
+    /// Start the producer and the loading bays, the stock is in shared
+    /// memory if `shared` is set
+    pub fn spawn(
+        idx: usize,
+        config: &MineConfig,
+        shared: bool,
+        dispatch: &'static str,
+    ) -> MineStore {
+        let stock = MineStock {
+            fuel: 0.0,
+            speed: config.production,
+            reserve: config.reserve,
+            loading: 0,
+            waiting: 0,
+        };
+        let store = MineStore::new(idx, stock, shared);
+        Self::launch(idx, config.clone(), store.clone(), dispatch);
+        store
+    }
+
+    /// Shared stock, the tank uses it to choose where to load
+    pub fn store(&self) -> MineStore {
+        self.store.clone()
+    }
+
+    /// Draw the loading bays above the vehicle parking and the waiting queue above them
+    pub fn draw(&self, draw: &Draw) {
+        let corner = match self.corner {
+            Some(c) => c,
+            None => return,
+        };
+        let stock = *self.store.lock();
+        let x = corner.x + BAY_SIZE;
+        let mut y = corner.y + 30.0;
+        for bay in 0..self.bays {
+            let busy = (bay as u32) < stock.loading;
+            draw.rect()
+                .w_h(BAY_SIZE, BAY_SIZE)
+                .x_y(x, y)
+                .color(if busy { ORANGE } else { DARK_CHARCOAL });
+            y += BAY_SIZE + 4.0;
+        }
+        for _ in 0..stock.waiting {
+            y += 4.0;
+            draw.rect()
+                .w_h(BAY_SIZE * 2.0, BAY_SIZE)
+                .x_y(x + BAY_SIZE / 2.0, y)
+                .color(GREEN);
+            y += BAY_SIZE;
         }
-        self.label = format!("{:.0}", fuel);
-        widget::Slider::new(fuel, 0., self.capacity)
+    }
+
+    /// Draw the gauge with its bottom right corner at the map `node` on the screen
+    pub fn update_ui(&mut self, ui: &mut UiCell, node: Point2) {
+        let (stock, waits) = {
+            let guard = self.store.lock();
+            let waits = match guard {
+                StockGuard::Shared(ref g) => Some(g.waits()),
+                StockGuard::Local(_) => None,
+            };
+            (*guard, waits)
+        };
+        let depleted = stock.is_depleted();
+        self.label = format!("{:.0}", stock.fuel);
+        widget::Slider::new(stock.fuel, 0., self.capacity)
             .label(&self.label)
             .enabled(false)
-            .w_h(self.height * 0.3, self.height)
+            .w_h(MINE_WIDTH, self.height)
             .label_font_size(20)
-            .color(color::GREEN)
+            .color(if depleted {
+                color::DARK_GREY
+            } else {
+                color::GREEN
+            })
             .label_rgb(1.0, 1.0, 1.0)
             .border(0.3)
-            .bottom_left_with_margin(20.0)
+            .x_y(
+                node.x as f64 - MINE_WIDTH / 2.0,
+                node.y as f64 + self.height / 2.0,
+            )
             .set(self.id, ui);
+        self.corner = ui
+            .rect_of(self.id)
+            .map(|r| pt2(r.x.end as f32, r.y.start as f32));
+
+        let speed = widget::Slider::new(self.speed_update, 0.0, 7.0)
+            .w_h(MINE_WIDTH, 10.0)
+            .rgb(1.0, 1.0, 0.3)
+            .border(0.0)
+            .up_from(self.id, 5.0)
+            .set(self.id_speed, ui)
+            .unwrap_or(self.speed_update);
+        if self.speed_update != speed {
+            self.speed_update = speed;
+            self.store.lock().speed = speed;
+        }
+
+        let mut info = Vec::new();
+        match stock.reserve {
+            Some(_) if depleted => info.push("depleted".to_string()),
+            Some(reserve) => info.push(format!("{:.0} left", reserve)),
+            None => (),
+        }
+        if let Some(waits) = waits {
+            info.push(format!("{} waits", waits));
+        }
+        if !info.is_empty() {
+            widget::Text::new(&info.join("\n"))
+                .font_size(12)
+                .rgb(1.0, 1.0, 1.0)
+                .up_from(self.id_speed, 5.0)
+                .set(self.id_info, ui);
+        }
     }
 
-    fn mkfifo() {
-        match mkfifo(MINE_PIPE, stat::Mode::S_IRWXU) {
-            Ok(_) => info!("Created named pipe {:?}", MINE_PIPE),
+    fn pipe_name(idx: usize) -> String {
+        format!("{}{}.pipe", MINE_PIPE_PREFIX, idx)
+    }
+
+    fn mkfifo(idx: usize) {
+        let pipe = Self::pipe_name(idx);
+        match mkfifo(pipe.as_str(), stat::Mode::S_IRWXU) {
+            Ok(_) => info!("Created named pipe {:?}", pipe),
             Err(Error::Sys(code)) => if code == Errno::EEXIST {},
             Err(err) => panic!("Error creating named pipe: {}", err),
         }
     }
 
-    fn fork() {
+    fn fork(idx: usize, store: &MineStore, config: &MineConfig) {
         let delay = Duration::from_millis(100);
-        Self::mkfifo();
         let parent_pid = getpid();
         match fork() {
             Ok(ForkResult::Parent { child, .. }) => {
@@ -82,10 +312,18 @@ impl Mine {
                 );
             }
             Ok(ForkResult::Child) => {
-                let mut pipe = Self::open_pipe_write();
+                let mut pipe = match store {
+                    MineStore::Local(_) => Some(Self::open_pipe_write(idx)),
+                    MineStore::Shared(_) => None,
+                };
                 loop {
                     let portion = random_f32();
-                    pipe.pack(portion).unwrap();
+                    match pipe {
+                        Some(ref mut pipe) => pipe.pack(portion).unwrap(),
+                        None => {
+                            store.lock().produce(portion, config);
+                        }
+                    }
                     thread::sleep(delay);
                     if parent_pid != getppid() {
                         warn!("Parent pid exited, exit");
@@ -97,67 +335,137 @@ impl Mine {
         }
     }
 
-    pub fn open_pipe_write() -> File {
+    pub fn open_pipe_write(idx: usize) -> File {
         OpenOptions::new()
-            .write(true)
             .append(true)
-            .open(MINE_PIPE)
+            .open(Self::pipe_name(idx))
             .expect("Open mine pipe in write mode")
     }
 
-    pub fn open_pipe_read() -> File {
+    pub fn open_pipe_read(idx: usize) -> File {
         OpenOptions::new()
             .read(true)
             .custom_flags(nix::libc::O_NONBLOCK)
-            .open(MINE_PIPE)
+            .open(Self::pipe_name(idx))
             .expect("Open mine pipe in read mode")
     }
 
-    fn launch(capacity: f32, fuel: Arc<Mutex<f32>>, speed: Arc<Mutex<f32>>) {
+    /// `dispatch` is the coordinator queue of the tanks which load at the mine
+    fn launch(idx: usize, config: MineConfig, store: MineStore, dispatch: &'static str) {
         thread::spawn(move || {
-            info!("Build Mine");
-            let delay = Duration::from_millis(100);
-
-            Self::mkfifo();
-            Self::fork();
-            let mut pipe = Self::open_pipe_read();
-            let mq_m = PMQ::open(MINE_QUEUE).nonblocking();
-            let mq_v = PMQ::open(VEHICLE_QUEUE);
+            info!("Build mine #{}", idx);
+            let delay = Duration::from_millis(50);
+
+            let mut pipe = match store {
+                MineStore::Local(_) => {
+                    Self::mkfifo(idx);
+                    Self::fork(idx, &store, &config);
+                    Some(Self::open_pipe_read(idx))
+                }
+                MineStore::Shared(_) => {
+                    Self::fork(idx, &store, &config);
+                    None
+                }
+            };
+            let q_name = format!("{}{}", MINE_QUEUE_PREFIX, idx);
+            let mq_m = PMQ::open(q_name.as_ref()).nonblocking();
+            let mq_dispatch = PMQ::open(dispatch);
+            let mut mq_v: HashMap<usize, PMQ> = HashMap::new();
+            let mut bays: Vec<Option<Bay>> = vec![None; config.bays];
+            let mut waiting: VecDeque<Bay> = VecDeque::new();
+            let mut depleted = false;
+            let mut last = Instant::now();
             loop {
-                let msg = mq_m.receive();
-                if let Ok(ref msg) = msg {
-                    trace!("Miner receive msg: {:?}", msg);
+                while let Ok(msg) = mq_m.receive() {
+                    trace!("Mine #{} receive msg: {:?}", idx, msg);
+                    match msg {
+                        Msg::Request(tank, amount) => {
+                            mq_v.entry(tank).or_insert_with(|| {
+                                PMQ::open(posixmq::vehicle_queue(tank).as_ref())
+                            });
+                            waiting.push_back(Bay { tank, left: amount })
+                        }
+                        msg => error!("Unsupported message for mine: {:?}", msg),
+                    }
+                }
+
+                // a tank holds at most one bay, its next requests wait in order
+                for slot in 0..bays.len() {
+                    if bays[slot].is_some() {
+                        continue;
+                    }
+                    let next = waiting
+                        .iter()
+                        .position(|w| bays.iter().flatten().all(|b| b.tank != w.tank));
+                    if let Some(pos) = next {
+                        bays[slot] = waiting.remove(pos);
+                    }
                 }
-                match msg {
-                    Ok(Msg::Fuel(mut amount)) => {
-                        let fourth = amount * 0.25;
-                        amount = f32::min(amount, *fuel.lock().unwrap());
-                        loop {
-                            thread::sleep(delay / 2);
-                            if amount <= 0.0 {
-                                mq_v.send(Msg::TankMove).expect("Send move to vehicle");
-                                break;
+
+                let now = Instant::now();
+                let dt = now.duration_since(last);
+                last = now;
+                let dt = dt.as_secs() as f32 + dt.subsec_millis() as f32 / 1000.0;
+                for slot in bays.iter_mut() {
+                    let done = match slot {
+                        Some(bay) => {
+                            let (val, empty) = {
+                                let mut stock = store.lock();
+                                let val = f32::min(bay.left, config.transfer_rate * dt);
+                                let val = f32::min(val, stock.fuel);
+                                if val > 0.0 {
+                                    stock.fuel -= val;
+                                    bay.left -= val;
+                                    ledger::transfer(-val, val);
+                                }
+                                (val, stock.fuel < EMPTY_LEVEL)
+                            };
+                            // a full tank queue must not hold the stock from the producer
+                            if val > 0.0 {
+                                mq_v[&bay.tank]
+                                    .send(Msg::Fuel(val))
+                                    .expect("Send fuel to vehicle");
                             }
-                            let val = f32::min(amount, fourth);
-                            *fuel.lock().unwrap() -= val;
-                            amount = f32::max(amount - val, 0.0);
-                            mq_v.send(Msg::Fuel(val)).expect("Send fuel to vehicle");
+                            bay.left <= 0.0 || empty
                         }
-                    }
-                    Ok(_) => unreachable!("Unsupported message for Mine!"),
-                    Err(_) => thread::sleep(delay),
-                };
-                match pipe.unpack::<f32>() {
-                    Ok(portion) => {
-                        let mut f = fuel.lock().unwrap();
-                        let portion = portion * *speed.lock().unwrap();
-                        if portion > 0.0 && *f < capacity {
-                            *f = f32::min(*f + portion, capacity);
-                            trace!("Miner mine fuel +val={:.3}, current={:.3}", portion, f);
+                        None => false,
+                    };
+                    if done {
+                        if let Some(bay) = slot.take() {
+                            mq_v[&bay.tank]
+                                .send(Msg::TankMove)
+                                .expect("Send move to vehicle");
                         }
                     }
-                    Err(_) => (),
                 }
+
+                if let Some(Ok(portion)) = pipe.as_mut().map(|p| p.unpack::<f32>()) {
+                    let mut stock = store.lock();
+                    let portion = stock.produce(portion, &config);
+                    if portion > 0.0 {
+                        trace!(
+                            "Mine #{} mine fuel +val={:.3}, current={:.3}",
+                            idx,
+                            portion,
+                            stock.fuel
+                        );
+                    }
+                }
+
+                {
+                    let mut stock = store.lock();
+                    stock.loading = bays.iter().flatten().count() as u32;
+                    stock.waiting = waiting.len() as u32;
+                    if !depleted && stock.is_depleted() {
+                        depleted = true;
+                        info!("Mine #{} is depleted", idx);
+                        // the coordinator tells every tank
+                        mq_dispatch
+                            .send(Msg::MineDepleted(idx))
+                            .expect("Send mine depleted to dispatch");
+                    }
+                }
+                thread::sleep(delay);
             }
         });
     }
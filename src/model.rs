@@ -1,20 +1,53 @@
+use crate::depot::Depot;
+use crate::dispatch::{MINE_POLICIES, STRATEGIES};
+use crate::fleet::Coordinator;
+use crate::grid::Grid;
+use crate::ledger;
+use crate::map::{Map, WORLD_WIDTH};
 use crate::mine::Mine;
-use crate::station::Station;
-use crate::vehicle::Vehicle;
+use crate::posixmq::{self, Msg, DEPOT_DISPATCH_QUEUE, DISPATCH_QUEUE, PMQ, STATUS_QUEUE};
+use crate::protocol;
+use crate::scenario::Scenario;
+use crate::station::{Station, StationStatus};
+use crate::tank::{Control, Role};
+use crate::vehicle::{TripStats, Vehicle};
 use nannou::prelude::*;
 use nannou::ui::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub const NUM_STATIONS: usize = 4;
+// pixels around a road which still count as a click on it
+const ROAD_TOLERANCE: f32 = 5.0;
+/// Time an external tank process has to publish its first status
+const EXTERNAL_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Model {
     pub ui: Ui,
     pub ids: Ids,
-    pub mining: f32,
+    /// Vehicle speed in world units (km) per second
     pub shipping: f32,
-    pub stations: [Station; NUM_STATIONS],
-    pub mine: Mine,
-    pub vehicle: Vehicle,
+    pub stations: Vec<Station>,
+    pub mines: Vec<Mine>,
+    pub depots: Vec<Depot>,
+    pub vehicles: Vec<Vehicle>,
+    pub map: Arc<Mutex<Map>>,
+    pub grid: Grid,
+    /// Index of the dispatch strategy in `STRATEGIES`
+    pub strategy: usize,
+    /// Index of the mine choice in `MINE_POLICIES`
+    pub mine_policy: usize,
+    status: PMQ,
     freeze: bool,
+    /// The player drives the first tank, the score is the station downtime
+    player: bool,
+    /// Seconds the stations stood still without fuel, summed over the stations
+    downtime: f32,
+    last_update: Instant,
+    /// External tanks which did not publish their status yet
+    unattached: Vec<usize>,
+    started: Instant,
+    /// The fuel ledger went off balance
+    imbalance: Option<String>,
 }
 
 impl Model {
@@ -26,28 +59,269 @@ impl Model {
             trace!("TODO: Unfreeze!");
         }
     }
+    /// Break or repair the station under the `point`
+    pub fn toggle_broken_at(&mut self, point: Point2) {
+        let p = [point.x as f64, point.y as f64];
+        for (idx, station) in self.stations.iter_mut().enumerate() {
+            if let Some(r) = self.ui.rect_of(self.ids.stations[idx]) {
+                if r.is_over(p) {
+                    station.toggle_broken();
+                }
+            }
+        }
+    }
+
+    /// Send the nearest tank supplying the stations to the station under the `point`,
+    /// `false` if there is no station there. The player unloads by hand instead.
+    pub fn dispatch_at(&mut self, point: Point2) -> bool {
+        if self.player {
+            return false;
+        }
+        let p = [point.x as f64, point.y as f64];
+        let station = (0..self.stations.len()).find(
+            |idx| matches!(self.ui.rect_of(self.ids.stations[*idx]), Some(r) if r.is_over(p)),
+        );
+        let idx = match station {
+            Some(idx) => idx,
+            None => return false,
+        };
+        let target = self.map.lock().unwrap().stations[idx];
+        let nearest = self
+            .vehicles
+            .iter()
+            .filter(|v| v.role() != Role::LongHaul)
+            .filter_map(|v| v.world().map(|w| (v, w.distance(target))))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((vehicle, _)) = nearest {
+            vehicle.dispatch(idx);
+        }
+        true
+    }
+
+    /// Close or open the road under the `point`, the tanks search another way
+    pub fn toggle_road_at(&mut self, point: Point2) {
+        // the click belongs to the widget
+        if self.ui.global_input().current.widget_under_mouse.is_some() {
+            return;
+        }
+        let scale = self.ui.win_w as f32 / WORLD_WIDTH;
+        self.map
+            .lock()
+            .unwrap()
+            .toggle_road_at(point / scale, ROAD_TOLERANCE / scale);
+    }
+
+    /// Steer the player's vehicle
+    pub fn steer(&mut self, direction: Vector2) {
+        if self.player {
+            self.vehicles[0].steer(direction);
+        }
+    }
+
+    pub fn player_load(&mut self) {
+        if self.player {
+            self.vehicles[0].player_load();
+        }
+    }
+
+    pub fn player_unload(&mut self) {
+        if self.player {
+            self.vehicles[0].player_unload();
+        }
+    }
+
+    pub fn resume_profiles(&mut self) {
+        info!("Stations follow their demand profiles");
+        for station in self.stations.iter_mut() {
+            station.resume_profile();
+        }
+    }
+
+    fn receive_status(&mut self) {
+        while let Ok(msg) = self.status.receive() {
+            match msg {
+                Msg::StationStatus(idx, fuel, rate, pid, broken) => {
+                    match self.stations.get_mut(idx) {
+                        Some(station) => station.set_status(StationStatus {
+                            fuel,
+                            rate,
+                            pid,
+                            broken,
+                        }),
+                        None => warn!("Status of unknown station #{}", idx),
+                    }
+                }
+                Msg::TankStatus(idx, state, target, mine) => {
+                    match self.vehicles.iter().find(|v| v.idx() == idx) {
+                        Some(vehicle) if vehicle.control() == Control::External => {
+                            let role = vehicle.role();
+                            let map = self.map.lock().unwrap();
+                            let known = map.contains(role.source(mine))
+                                && target.is_none_or(|t| map.contains(role.target(t)));
+                            drop(map);
+                            if !known {
+                                warn!(
+                                    "Status of external tank #{} with unknown station {:?} or mine #{}",
+                                    idx, target, mine
+                                );
+                                continue;
+                            }
+                            if self.unattached.contains(&idx) {
+                                info!("External tank #{} attached", idx);
+                                self.unattached.retain(|t| *t != idx);
+                            }
+                            vehicle.set_status(state, target, mine);
+                        }
+                        _ => warn!("Status of unknown external tank #{}", idx),
+                    }
+                }
+                msg => warn!("Unexpected status message: {:?}", msg),
+            }
+        }
+    }
+
     pub fn update(&mut self) {
+        self.receive_status();
+        if !self.unattached.is_empty() && self.started.elapsed() > EXTERNAL_TIMEOUT {
+            error!(
+                "No external tank process attached to {} within {}s, start it with the simulation",
+                self.unattached
+                    .iter()
+                    .map(|idx| posixmq::vehicle_queue(*idx))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                EXTERNAL_TIMEOUT.as_secs()
+            );
+            std::process::exit(1);
+        }
+        let dt = self.last_update.elapsed();
+        self.last_update = Instant::now();
+        let dt = dt.as_secs() as f32 + dt.subsec_micros() as f32 / 1e6;
+        match ledger::check() {
+            Err(e) => {
+                if self.imbalance.is_none() {
+                    error!("{}", e);
+                }
+                self.imbalance = Some(e);
+            }
+            Ok(()) => self.imbalance = None,
+        }
+        let idle = self.stations.iter().filter(|s| s.is_out_of_fuel()).count();
+        self.downtime += idle as f32 * dt;
         let ui = &mut self.ui.set_widgets();
 
         // Controls
-        self.mining = Self::build_slider(self.mining, 7., "Mining")
+        self.shipping = Self::build_slider(self.shipping, 50., "Speed km/s")
             .top_left_with_margin(20.0)
-            .set(self.ids.mining, ui)
-            .unwrap_or(self.mining);
-        self.shipping = Self::build_slider(self.shipping, 7., "Shipping")
-            .down(10.0)
             .set(self.ids.shipping, ui)
             .unwrap_or(self.shipping);
 
-        // Mine
-        self.mine.update_ui(ui, self.mining);
+        let scale = ui.win_w as f32 / WORLD_WIDTH;
+        // Mines
+        let map = self.map.lock().unwrap();
+        for (mine, p) in self.mines.iter_mut().zip(map.mines.iter()) {
+            mine.update_ui(ui, *p * scale);
+        }
+        // Depots
+        for (depot, p) in self.depots.iter_mut().zip(map.depots.iter()) {
+            depot.update_ui(ui, *p * scale);
+        }
         // Stations
-        for station in self.stations.iter_mut() {
-            station.update(ui);
+        for (station, p) in self.stations.iter_mut().zip(map.stations.iter()) {
+            station.update(ui, *p * scale);
+        }
+        drop(map);
+        let supplied = self.stations.iter().map(Station::power).sum();
+        self.grid.update(ui, self.ids.shipping, supplied);
+
+        // Trips
+        let mut stats = TripStats::default();
+        for vehicle in self.vehicles.iter() {
+            stats.add(&vehicle.stats);
+        }
+        let mut trips = format!(
+            "trips: {}, {:.0} km, {:.1} fuel",
+            stats.trips, stats.distance, stats.fuel
+        );
+        if let Some((distance, time, fuel)) = stats.last {
+            trips += &format!(
+                "\nlast: {:.0} km, {}.{}s, {:.1} fuel",
+                distance,
+                time.as_secs(),
+                time.subsec_millis() / 100,
+                fuel
+            );
+        }
+        if self.player {
+            trips += &format!("\nstation downtime: {:.0}s", self.downtime);
+        }
+        widget::Text::new(&trips)
+            .font_size(12)
+            .rgb(0.8, 0.8, 0.8)
+            .down_from(self.ids.grid_stats, 5.0)
+            .set(self.ids.trips, ui);
+
+        // Dispatch
+        let unattached = &self.unattached;
+        let modes: Vec<String> = self
+            .vehicles
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| v.role() != Role::LongHaul)
+            .map(|(idx, v)| match v.manual() {
+                _ if unattached.contains(&v.idx()) => {
+                    format!("tank #{}: waiting for the external process", idx)
+                }
+                Some(station) => format!("tank #{}: manual to #{}", idx, station),
+                None => format!("tank #{}: auto", idx),
+            })
+            .collect();
+        widget::Text::new(&modes.join("\n"))
+            .font_size(12)
+            .rgb(0.8, 0.8, 0.8)
+            .down_from(self.ids.trips, 5.0)
+            .set(self.ids.dispatch_mode, ui);
+        if let Some(idx) = widget::DropDownList::new(&STRATEGIES, Some(self.strategy))
+            .w_h(200.0, 25.0)
+            .label_font_size(12)
+            .rgb(0.3, 0.3, 0.3)
+            .label_rgb(1.0, 1.0, 1.0)
+            .border(0.0)
+            .down_from(self.ids.dispatch_mode, 10.0)
+            .align_left_of(self.ids.shipping)
+            .set(self.ids.dispatch, ui)
+        {
+            self.strategy = idx;
+            for vehicle in self.vehicles.iter_mut() {
+                vehicle.set_strategy(idx);
+            }
+        }
+        if let Some(idx) = widget::DropDownList::new(&MINE_POLICIES, Some(self.mine_policy))
+            .w_h(200.0, 25.0)
+            .label_font_size(12)
+            .rgb(0.3, 0.3, 0.3)
+            .label_rgb(1.0, 1.0, 1.0)
+            .border(0.0)
+            .down_from(self.ids.dispatch, 5.0)
+            .set(self.ids.mine_policy, ui)
+        {
+            self.mine_policy = idx;
+            for vehicle in self.vehicles.iter_mut() {
+                vehicle.set_mine_policy(idx);
+            }
+        }
+        if let Some(imbalance) = &self.imbalance {
+            widget::Text::new(imbalance)
+                .font_size(12)
+                .rgb(1.0, 0.3, 0.3)
+                .down_from(self.ids.mine_policy, 5.0)
+                .set(self.ids.ledger, ui);
         }
 
         // update only after stations and mine
-        self.vehicle.update(ui, self.shipping / 100.0);
+        for vehicle in self.vehicles.iter_mut() {
+            vehicle.update(ui, self.shipping);
+        }
     }
 
     fn build_slider(val: f32, max: f32, label: &'static str) -> widget::Slider<'static, f32> {
@@ -64,16 +338,44 @@ impl Model {
 widget_ids! {
     #[derive(Clone)]
     pub struct Ids {
-        mining,
         shipping,
         stations[],
         burning[],
-        mine,
-        vehicle,
+        mines[],
+        mining[],
+        mine_info[],
+        depots[],
+        vehicles,
+        map,
+        grid,
+        grid_stats,
+        trips,
+        dispatch_mode,
+        dispatch,
+        mine_policy,
+        ledger,
     }
 }
 
 pub fn model(app: &App) -> Model {
+    let scenario = Scenario::from_args();
+    posixmq::cleanup_posix_queues(&scenario);
+    if let Some(path) = &scenario.trace {
+        // the external tanks read their queues out of sight
+        let external = if scenario.external_tank {
+            (0..scenario.fleet.tanks)
+                .map(posixmq::vehicle_queue)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        protocol::start_trace(path, external);
+    }
+    // the books can't follow the fuel of a tank run elsewhere
+    if !scenario.external_tank {
+        ledger::start();
+    }
+
     app.new_window()
         .with_multisampling(0)
         .with_title("Power station")
@@ -94,29 +396,135 @@ pub fn model(app: &App) -> Model {
 
     let mut ids = Ids::new(ui.widget_id_generator());
     ids.stations
-        .resize(NUM_STATIONS, &mut ui.widget_id_generator());
+        .resize(scenario.stations.len(), &mut ui.widget_id_generator());
     ids.burning
-        .resize(NUM_STATIONS, &mut ui.widget_id_generator());
-
-    let stations = [
-        // stations are drawn in reverse order, recover order here
-        Station::new(0, ids.stations[0], ids.burning[0]),
-        Station::new(1, ids.stations[1], ids.burning[1]),
-        Station::new(2, ids.stations[2], ids.burning[2]),
-        Station::new(3, ids.stations[3], ids.burning[3]),
-    ];
-    assert_eq!(stations.len(), NUM_STATIONS);
-    let mine = Mine::new(ids.mine);
-    let vehicle = Vehicle::new(ids.clone());
+        .resize(scenario.stations.len(), &mut ui.widget_id_generator());
+    ids.mines
+        .resize(scenario.mines.len(), &mut ui.widget_id_generator());
+    ids.mining
+        .resize(scenario.mines.len(), &mut ui.widget_id_generator());
+    ids.mine_info
+        .resize(scenario.mines.len(), &mut ui.widget_id_generator());
+    ids.depots
+        .resize(scenario.depots.len(), &mut ui.widget_id_generator());
+
+    let status = PMQ::open(STATUS_QUEUE).nonblocking();
+    let largest = scenario
+        .stations
+        .iter()
+        .map(|s| s.capacity)
+        .fold(0.0, f32::max);
+    let stations = scenario
+        .stations
+        .iter()
+        .enumerate()
+        .map(|(idx, config)| {
+            Station::new(
+                idx,
+                ids.stations[idx],
+                ids.burning[idx],
+                config,
+                largest,
+                scenario.station_procs,
+            )
+        })
+        .collect();
+    let mines: Vec<Mine> = scenario
+        .mines
+        .iter()
+        .enumerate()
+        .map(|(idx, config)| {
+            Mine::new(
+                idx,
+                ids.mines[idx],
+                ids.mining[idx],
+                ids.mine_info[idx],
+                config,
+                scenario.mine_shm,
+                if scenario.depots.is_empty() {
+                    DISPATCH_QUEUE
+                } else {
+                    DEPOT_DISPATCH_QUEUE
+                },
+            )
+        })
+        .collect();
+    let depots: Vec<Depot> = scenario
+        .depots
+        .iter()
+        .enumerate()
+        .map(|(idx, config)| Depot::new(idx, ids.depots[idx], config))
+        .collect();
+    let map = Arc::new(Mutex::new(Map::new(&scenario)));
+    let tanks = scenario.fleet.tanks;
+    // the player drives alone
+    let local = if scenario.player { 1 } else { tanks };
+    let control = if scenario.player {
+        Control::Player
+    } else if scenario.external_tank {
+        Control::External
+    } else {
+        Control::Auto
+    };
+    let coordinator = Coordinator::spawn(DISPATCH_QUEUE, 0..local);
+    let mut vehicles: Vec<Vehicle> = (0..local)
+        .map(|idx| {
+            let (role, sources) = if depots.is_empty() {
+                (Role::Direct, mines.iter().map(Mine::store).collect())
+            } else {
+                (Role::Local, depots.iter().map(Depot::store).collect())
+            };
+            Vehicle::new(
+                idx,
+                role,
+                control,
+                &scenario.fleet,
+                map.clone(),
+                sources,
+                coordinator.clone(),
+            )
+        })
+        .collect();
+    if !depots.is_empty() {
+        let haulers = tanks..scenario.vehicles();
+        let coordinator = Coordinator::spawn(DEPOT_DISPATCH_QUEUE, haulers.clone());
+        vehicles.extend(haulers.map(|idx| {
+            Vehicle::new(
+                idx,
+                Role::LongHaul,
+                Control::Auto,
+                &scenario.fleet,
+                map.clone(),
+                mines.iter().map(Mine::store).collect(),
+                coordinator.clone(),
+            )
+        }));
+    }
+    let grid = Grid::new(ids.grid, ids.grid_stats, scenario.demand.clone());
 
     Model {
         ui,
         ids,
-        mining: 2.0,
-        shipping: 3.0,
+        shipping: 20.0,
         stations,
-        mine,
-        vehicle,
+        mines,
+        depots,
+        vehicles,
+        map,
+        grid,
+        strategy: STRATEGIES.len() - 1,
+        mine_policy: 0,
+        status,
         freeze: false,
+        player: scenario.player,
+        downtime: 0.0,
+        last_update: Instant::now(),
+        unattached: if scenario.external_tank {
+            (0..local).collect()
+        } else {
+            Vec::new()
+        },
+        started: Instant::now(),
+        imbalance: None,
     }
 }
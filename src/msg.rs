@@ -0,0 +1,253 @@
+//! The messages of the queues and their text encoding, "word args…" with
+//! the arguments separated by spaces. Nothing here depends on the rest of
+//! the simulation, so the fuzz target builds it on its own.
+
+use std::str::{self, FromStr, SplitWhitespace};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TankState {
+    Refill(f32),
+    Supply(f32),
+    Load(f32),
+    Unload(f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Msg {
+    /// Station index, its fuel level and burn rate
+    IdleStation(usize, f32, f32),
+    Fuel(f32),
+    TankLoad,
+    TankUnload,
+    TankMove,
+    MineDepleted(usize),
+    /// Manual burn rate of a station
+    BurnRate(f32),
+    /// Station burns according to its demand profile
+    FollowProfile,
+    /// Station index, fuel level, burn rate, pid of the reporting process
+    /// and whether the station is broken
+    StationStatus(usize, f32, f32, i32, bool),
+    /// Break or repair a station
+    ToggleBroken,
+    /// A broken station returns the fuel delivered to it
+    Rejected(usize, f32),
+    /// Tank index and the fuel it asks a mine for
+    Request(usize, f32),
+    /// Tank index and the fuel it delivers to a station, zero when it leaves
+    Delivery(usize, f32),
+    /// Fuel the vehicle burned on the road
+    Consumed(f32),
+    /// Station the tank goes to next, picked by hand over the strategy
+    Dispatch(usize),
+    /// Tank index, its state, target station and mine, published by an external tank
+    TankStatus(usize, TankState, Option<usize>, usize),
+}
+
+/// The next argument parsed as `T`
+fn arg<T: FromStr>(args: &mut SplitWhitespace) -> Option<T> {
+    args.next()?.parse().ok()
+}
+
+/// The next argument as a finite fuel amount, level, rate or percent
+fn amount(args: &mut SplitWhitespace) -> Option<f32> {
+    arg::<f32>(args).filter(|a| a.is_finite())
+}
+
+/// 0 or 1
+fn flag(args: &mut SplitWhitespace) -> Option<bool> {
+    match arg::<u8>(args)? {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+/// The phase number followed by the fuel percent
+fn tank_state(args: &mut SplitWhitespace) -> Option<TankState> {
+    let phase = arg::<u8>(args)?;
+    let percent = amount(args)?;
+    match phase {
+        0 => Some(TankState::Refill(percent)),
+        1 => Some(TankState::Supply(percent)),
+        2 => Some(TankState::Load(percent)),
+        3 => Some(TankState::Unload(percent)),
+        _ => None,
+    }
+}
+
+/// A station index, or -1 for none
+fn target(args: &mut SplitWhitespace) -> Option<Option<usize>> {
+    match args.next()? {
+        "-1" => Some(None),
+        idx => idx.parse().ok().map(Some),
+    }
+}
+
+impl Msg {
+    pub fn encode(self) -> Vec<u8> {
+        match self {
+            Msg::IdleStation(idx, level, rate) => format!("idle {} {} {}", idx, level, rate).into(),
+            Msg::Fuel(amount) => format!("fuel {}", amount).into(),
+            Msg::TankLoad => "load".into(),
+            Msg::TankUnload => "unload".into(),
+            Msg::TankMove => "move".into(),
+            Msg::MineDepleted(idx) => format!("depleted {}", idx).into(),
+            Msg::BurnRate(rate) => format!("burn {}", rate).into(),
+            Msg::FollowProfile => "profile".into(),
+            Msg::StationStatus(idx, fuel, rate, pid, broken) => {
+                format!("status {} {} {} {} {}", idx, fuel, rate, pid, broken as u8).into()
+            }
+            Msg::ToggleBroken => "toggle".into(),
+            Msg::Rejected(idx, amount) => format!("rejected {} {}", idx, amount).into(),
+            Msg::Request(tank, amount) => format!("request {} {}", tank, amount).into(),
+            Msg::Delivery(tank, amount) => format!("delivery {} {}", tank, amount).into(),
+            Msg::Consumed(fuel) => format!("consumed {}", fuel).into(),
+            Msg::Dispatch(idx) => format!("dispatch {}", idx).into(),
+            Msg::TankStatus(idx, state, target, mine) => {
+                let (phase, percent) = match state {
+                    TankState::Refill(p) => (0, p),
+                    TankState::Supply(p) => (1, p),
+                    TankState::Load(p) => (2, p),
+                    TankState::Unload(p) => (3, p),
+                };
+                let target = match target {
+                    Some(t) => t.to_string(),
+                    None => "-1".to_string(),
+                };
+                format!("tank {} {} {} {} {}", idx, phase, percent, target, mine).into()
+            }
+        }
+    }
+
+    /// The message `encode` turned into `data`, `None` on an unknown word,
+    /// a missing, extra or malformed argument
+    pub fn decode(data: &[u8]) -> Option<Msg> {
+        let mut args = str::from_utf8(data).ok()?.split_whitespace();
+        let args = &mut args;
+        let msg = match args.next()? {
+            "idle" => Msg::IdleStation(arg(args)?, amount(args)?, amount(args)?),
+            "fuel" => Msg::Fuel(amount(args)?),
+            "load" => Msg::TankLoad,
+            "unload" => Msg::TankUnload,
+            "move" => Msg::TankMove,
+            "depleted" => Msg::MineDepleted(arg(args)?),
+            "burn" => Msg::BurnRate(amount(args)?),
+            "profile" => Msg::FollowProfile,
+            "status" => Msg::StationStatus(
+                arg(args)?,
+                amount(args)?,
+                amount(args)?,
+                arg(args)?,
+                flag(args)?,
+            ),
+            "toggle" => Msg::ToggleBroken,
+            "rejected" => Msg::Rejected(arg(args)?, amount(args)?),
+            "request" => Msg::Request(arg(args)?, amount(args)?),
+            "delivery" => Msg::Delivery(arg(args)?, amount(args)?),
+            "consumed" => Msg::Consumed(amount(args)?),
+            "dispatch" => Msg::Dispatch(arg(args)?),
+            "tank" => Msg::TankStatus(arg(args)?, tank_state(args)?, target(args)?, arg(args)?),
+            _ => return None,
+        };
+        match args.next() {
+            Some(_) => None,
+            None => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Any finite fuel amount, the decoder rejects the rest
+    fn amount() -> impl Strategy<Value = f32> {
+        any::<f32>().prop_filter("a finite number", |x| x.is_finite())
+    }
+
+    fn tank_state() -> impl Strategy<Value = TankState> {
+        prop_oneof![
+            amount().prop_map(TankState::Refill),
+            amount().prop_map(TankState::Supply),
+            amount().prop_map(TankState::Load),
+            amount().prop_map(TankState::Unload),
+        ]
+    }
+
+    fn msg() -> impl Strategy<Value = Msg> {
+        prop_oneof![
+            (any::<usize>(), amount(), amount()).prop_map(|(i, l, r)| Msg::IdleStation(i, l, r)),
+            amount().prop_map(Msg::Fuel),
+            Just(Msg::TankLoad),
+            Just(Msg::TankUnload),
+            Just(Msg::TankMove),
+            any::<usize>().prop_map(Msg::MineDepleted),
+            amount().prop_map(Msg::BurnRate),
+            Just(Msg::FollowProfile),
+            (
+                any::<usize>(),
+                amount(),
+                amount(),
+                any::<i32>(),
+                any::<bool>()
+            )
+                .prop_map(|(i, f, r, p, b)| Msg::StationStatus(i, f, r, p, b)),
+            Just(Msg::ToggleBroken),
+            (any::<usize>(), amount()).prop_map(|(i, a)| Msg::Rejected(i, a)),
+            (any::<usize>(), amount()).prop_map(|(t, a)| Msg::Request(t, a)),
+            (any::<usize>(), amount()).prop_map(|(t, a)| Msg::Delivery(t, a)),
+            amount().prop_map(Msg::Consumed),
+            any::<usize>().prop_map(Msg::Dispatch),
+            (
+                any::<usize>(),
+                tank_state(),
+                any::<Option<usize>>(),
+                any::<usize>()
+            )
+                .prop_map(|(i, s, t, m)| Msg::TankStatus(i, s, t, m)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn every_message_survives_a_round_trip(msg in msg()) {
+            prop_assert_eq!(Msg::decode(&msg.clone().encode()), Some(msg));
+        }
+
+        #[test]
+        fn any_bytes_decode_without_a_panic(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = Msg::decode(&data);
+        }
+
+        #[test]
+        fn any_words_decode_without_a_panic(text in "[a-z]{0,9}( [-+0-9.a-z]{0,8}){0,6}") {
+            let _ = Msg::decode(text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn malformed_messages_decode_to_none() {
+        for text in &[
+            "",
+            " ",
+            "launch 1",
+            "fuel",
+            "fuel x",
+            "fuel 1 2",
+            "dispatch -3",
+            "dispatch 1.5",
+            "status 0 1 2 3 2",
+            "tank 0 4 50 -1 0",
+            "tank 0 1 50 -2 0",
+            "idle 0 NaN 1",
+            "fuel inf",
+            "delivery 0 -inf",
+            "tank 0 1 nan -1 0",
+        ] {
+            assert_eq!(Msg::decode(text.as_bytes()), None, "{:?}", text);
+        }
+        assert_eq!(Msg::decode(&[0xff, 0xfe]), None);
+    }
+}
@@ -1,14 +1,21 @@
-use crate::model::NUM_STATIONS;
+pub use crate::msg::Msg;
+use crate::protocol;
+use crate::scenario::Scenario;
 use posixmq::{unlink, OpenOptions, PosixMq};
 use std::io;
-use std::str::FromStr;
 
-pub const MINE_QUEUE: &str = "/mq-m";
-pub const VEHICLE_QUEUE: &str = "/mq-v";
+pub const MINE_QUEUE_PREFIX: &str = "/mq-m";
+pub const VEHICLE_QUEUE_PREFIX: &str = "/mq-v";
+pub const DISPATCH_QUEUE: &str = "/mq-dispatch";
 pub const STATION_QUEUE_PREFIX: &str = "/mq-s";
+pub const STATUS_QUEUE: &str = "/mq-status";
+pub const DEPOT_QUEUE_PREFIX: &str = "/mq-d";
+/// Depot requests for the long-haul tanks
+pub const DEPOT_DISPATCH_QUEUE: &str = "/mq-depot-dispatch";
 
 pub struct PMQ {
     q: PosixMq,
+    name: String,
 }
 
 impl PMQ {
@@ -18,6 +25,7 @@ impl PMQ {
                 .create()
                 .open(name)
                 .expect("Create posix message queue"),
+            name: name.to_string(),
         }
     }
 
@@ -31,61 +39,59 @@ impl PMQ {
     }
 
     pub fn send(&self, m: Msg) -> Result<(), io::Error> {
-        let msg = m.encode();
-        self.q.send(0, &msg[..])
+        self.q.send(0, &m.clone().encode()[..])?;
+        protocol::record(&self.name, &m, true);
+        Ok(())
     }
 
+    /// The next message, the ones which don't decode are dropped
     pub fn receive(&self) -> Result<Msg, io::Error> {
         let mut buf = vec![0; self.q.attributes().max_msg_len];
-        let (_, len) = self.q.receive(&mut buf)?;
-        let msg = String::from_utf8(buf[..len].to_vec()).expect("Decode message payload");
-        Ok(Msg::decode(msg))
+        loop {
+            let (_, len) = self.q.receive(&mut buf)?;
+            match Msg::decode(&buf[..len]) {
+                Some(msg) => {
+                    protocol::record(&self.name, &msg, false);
+                    return Ok(msg);
+                }
+                None => warn!(
+                    "Drop malformed message {:?} on {}",
+                    String::from_utf8_lossy(&buf[..len]),
+                    self.name
+                ),
+            }
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum Msg {
-    IdleStation(usize),
-    Fuel(f32),
-    TankLoad,
-    TankUnload,
-    TankMove,
+/// Queue of the tank `tank` of the fleet
+pub fn vehicle_queue(tank: usize) -> String {
+    format!("{}{}", VEHICLE_QUEUE_PREFIX, tank)
 }
 
-impl Msg {
-    fn encode(self) -> Vec<u8> {
-        match self {
-            Msg::IdleStation(idx) => format!("idle {}", idx).into(),
-            Msg::Fuel(amount) => format!("fuel {}", amount).into(),
-            Msg::TankLoad => "load".into(),
-            Msg::TankUnload => "unload".into(),
-            Msg::TankMove => "move".into(),
-        }
+pub fn cleanup_posix_queues(scenario: &Scenario) {
+    info!("Unlink posix message queues");
+    for i in 0..scenario.mines.len() {
+        let q_name = format!("{}{}", MINE_QUEUE_PREFIX, i);
+        info!("unlink {}: {:?}", q_name, unlink(&q_name));
     }
-    fn decode(data: String) -> Msg {
-        let data: Vec<_> = data.split_whitespace().collect();
-        let atype = data[0];
-        let mut payload = 0.0;
-        if data.len() > 1 {
-            payload = f32::from_str(data[1]).expect("Parse message payload");
-        }
-        match atype {
-            "idle" => Msg::IdleStation(payload as usize),
-            "fuel" => Msg::Fuel(payload),
-            "load" => Msg::TankLoad,
-            "unload" => Msg::TankUnload,
-            "move" => Msg::TankMove,
-            _ => unreachable!("Unexpected message type {}", atype),
-        }
+    for i in 0..scenario.vehicles() {
+        let q_name = vehicle_queue(i);
+        info!("unlink {}: {:?}", q_name, unlink(&q_name));
     }
-}
-
-pub fn cleanup_posix_queues() {
-    info!("Unlink posix message queues");
-    info!("unlink {}: {:?}", MINE_QUEUE, unlink(MINE_QUEUE));
-    info!("unlink {}: {:?}", VEHICLE_QUEUE, unlink(VEHICLE_QUEUE));
-    for i in 0..NUM_STATIONS {
+    info!("unlink {}: {:?}", DISPATCH_QUEUE, unlink(DISPATCH_QUEUE));
+    info!(
+        "unlink {}: {:?}",
+        DEPOT_DISPATCH_QUEUE,
+        unlink(DEPOT_DISPATCH_QUEUE)
+    );
+    info!("unlink {}: {:?}", STATUS_QUEUE, unlink(STATUS_QUEUE));
+    for i in 0..scenario.stations.len() {
         let q_name = format!("{}{}", STATION_QUEUE_PREFIX, i);
         info!("unlink {}: {:?}", q_name, unlink(&q_name));
     }
+    for i in 0..scenario.depots.len() {
+        let q_name = format!("{}{}", DEPOT_QUEUE_PREFIX, i);
+        info!("unlink {}: {:?}", q_name, unlink(&q_name));
+    }
 }
@@ -0,0 +1,403 @@
+use crate::map::Node;
+use crate::msg::TankState;
+use crate::posixmq::{
+    Msg, DEPOT_DISPATCH_QUEUE, DEPOT_QUEUE_PREFIX, DISPATCH_QUEUE, MINE_QUEUE_PREFIX,
+    STATION_QUEUE_PREFIX, STATUS_QUEUE, VEHICLE_QUEUE_PREFIX,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::Mutex;
+
+/// Rounding of the fuel chunks the checker lets pass
+const TOLERANCE: f32 = 1e-3;
+
+/// Trace file shared by the threads and the forked processes, one
+/// `<queue> <message>` line per message
+struct Trace {
+    out: File,
+    /// Queues read outside of the simulation, recorded when sent
+    external: Vec<String>,
+}
+
+static TRACE: Mutex<Option<Trace>> = Mutex::new(None);
+
+/// Record the message stream to the file at `path` from now on: the messages
+/// when they are received, or when they are sent to one of the `external` queues
+pub fn start_trace(path: &str, external: Vec<String>) {
+    File::create(path).expect("Create protocol trace");
+    // the lines of the forked processes land after each other
+    let out = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .expect("Open protocol trace");
+    info!("Record the messages to {}", path);
+    *TRACE.lock().unwrap() = Some(Trace { out, external });
+}
+
+/// Add the message on the `queue` to the trace, if any
+pub fn record(queue: &str, msg: &Msg, sent: bool) {
+    // the station reports are not part of the protocol
+    if let Msg::StationStatus(..) = msg {
+        return;
+    }
+    let mut trace = TRACE.lock().unwrap();
+    if let Some(trace) = trace.as_mut() {
+        if sent && !trace.external.iter().any(|q| q == queue) {
+            return;
+        }
+        let text = String::from_utf8(msg.clone().encode()).expect("Encode message");
+        if let Err(e) = writeln!(trace.out, "{} {}", queue, text) {
+            warn!("Can't record {:?}: {}", msg, e);
+        }
+    }
+}
+
+/// The queue a message went to
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Endpoint {
+    Tank(usize),
+    Node(Node),
+    /// Station requests
+    Dispatch,
+    /// Depot requests
+    DepotDispatch,
+    /// Tank states
+    Status,
+}
+
+impl Endpoint {
+    fn parse(queue: &str) -> Option<Self> {
+        let idx = |prefix: &str| queue.strip_prefix(prefix).and_then(|i| i.parse().ok());
+        match queue {
+            DISPATCH_QUEUE => Some(Endpoint::Dispatch),
+            DEPOT_DISPATCH_QUEUE => Some(Endpoint::DepotDispatch),
+            STATUS_QUEUE => Some(Endpoint::Status),
+            _ => idx(VEHICLE_QUEUE_PREFIX)
+                .map(Endpoint::Tank)
+                .or_else(|| idx(MINE_QUEUE_PREFIX).map(|i| Endpoint::Node(Node::Mine(i))))
+                .or_else(|| idx(STATION_QUEUE_PREFIX).map(|i| Endpoint::Node(Node::Station(i))))
+                .or_else(|| idx(DEPOT_QUEUE_PREFIX).map(|i| Endpoint::Node(Node::Depot(i)))),
+        }
+    }
+}
+
+/// What the checker knows about a tank from its messages
+#[derive(Default)]
+struct TankTrace {
+    /// Station or depot the tank last reported to supply, `None` while it
+    /// loads or has no target
+    target: Option<usize>,
+    /// Between the first portion delivered and leaving the station
+    delivering: bool,
+    /// Fuel the tank may still get: requested at the mine, or delivered
+    /// to a station which may return the rest
+    allowance: f32,
+    /// Requests and departures the mine or the station did not answer with Move yet
+    open: usize,
+}
+
+/// First message which breaks a rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    /// Line of the trace, from 1
+    pub line: usize,
+    pub queue: String,
+    pub msg: Msg,
+    pub rule: &'static str,
+}
+
+/// Outcome of a trace check, passed if there is no violation
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub messages: usize,
+    pub violation: Option<Violation>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.violation.is_none()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.violation {
+            None => write!(f, "PASS: {} messages follow the protocol", self.messages),
+            Some(v) => write!(
+                f,
+                "FAIL at line {}: {} {:?}\n  {}",
+                v.line, v.queue, v.msg, v.rule
+            ),
+        }
+    }
+}
+
+/// Follows the messages between the mines, the tanks and the stations
+/// and tells the first one out of the protocol order
+#[derive(Default)]
+pub struct Checker {
+    tanks: HashMap<usize, TankTrace>,
+    /// Stations and depots which asked for fuel and were not left yet
+    idle: HashSet<Node>,
+}
+
+impl Checker {
+    /// Take the message sent to the `queue`, the broken rule if it is out of order
+    pub fn observe(&mut self, queue: &str, msg: &Msg) -> Result<(), &'static str> {
+        match (Endpoint::parse(queue), msg) {
+            (Some(Endpoint::Dispatch), Msg::IdleStation(idx, ..)) => {
+                self.idle.insert(Node::Station(*idx));
+            }
+            (Some(Endpoint::DepotDispatch), Msg::IdleStation(idx, ..)) => {
+                self.idle.insert(Node::Depot(*idx));
+            }
+            (Some(Endpoint::Status), Msg::TankStatus(t, state, target, _)) => {
+                let tank = self.tanks.entry(*t).or_default();
+                tank.target = match state {
+                    TankState::Supply(_) | TankState::Unload(_) => *target,
+                    TankState::Refill(_) | TankState::Load(_) => None,
+                };
+            }
+            (Some(Endpoint::Tank(t)), msg) => {
+                let tank = self.tanks.entry(t).or_default();
+                match msg {
+                    Msg::Dispatch(idx) => {
+                        // the operator asks for the station
+                        self.idle.insert(Node::Station(*idx));
+                    }
+                    Msg::Fuel(amount) => {
+                        tank.allowance -= amount;
+                        if tank.allowance < -TOLERANCE {
+                            return Err("Fuel beyond the amount the tank requested");
+                        }
+                    }
+                    Msg::TankUnload if !tank.delivering && tank.target.is_none() => {
+                        return Err(
+                            "TankUnload before the tank arrived at a station it was sent to",
+                        );
+                    }
+                    Msg::TankMove => {
+                        tank.open = tank.open.saturating_sub(1);
+                        if tank.open == 0 {
+                            tank.allowance = 0.0;
+                        }
+                    }
+                    Msg::Rejected(..) => tank.delivering = false,
+                    _ => (),
+                }
+            }
+            (Some(Endpoint::Node(node)), Msg::Request(t, amount)) => {
+                let tank = self.tanks.entry(*t).or_default();
+                tank.allowance += amount;
+                tank.open += 1;
+                if let Node::Station(_) = node {
+                    return Err("Request sent to a station");
+                }
+            }
+            (Some(Endpoint::Node(node)), Msg::Delivery(t, amount)) => {
+                let tank = self.tanks.entry(*t).or_default();
+                if *amount > 0.0 {
+                    if !self.idle.contains(&node) {
+                        return Err("Fuel delivered to a station which did not ask for it");
+                    }
+                    // the station returns what it can't take
+                    tank.allowance += amount;
+                    tank.delivering = true;
+                } else {
+                    tank.delivering = false;
+                    tank.open += 1;
+                    self.idle.remove(&node);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Replay a trace, stop at the first violation
+    pub fn check<R: BufRead>(trace: R) -> io::Result<Report> {
+        let mut checker = Checker::default();
+        let mut messages = 0;
+        for (n, line) in trace.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (queue, text) = match line.find(' ') {
+                Some(at) => (&line[..at], &line[at + 1..]),
+                None => (line, ""),
+            };
+            let msg = match Msg::decode(text.as_bytes()) {
+                Some(msg) => msg,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: malformed message {:?}", n + 1, text),
+                    ))
+                }
+            };
+            messages += 1;
+            if let Err(rule) = checker.observe(queue, &msg) {
+                return Ok(Report {
+                    messages,
+                    violation: Some(Violation {
+                        line: n + 1,
+                        queue: queue.to_string(),
+                        msg,
+                        rule,
+                    }),
+                });
+            }
+        }
+        Ok(Report {
+            messages,
+            violation: None,
+        })
+    }
+}
+
+/// Check the trace file at `path` and print the report, the exit code of the check
+pub fn check_file(path: &str) -> i32 {
+    let report = match File::open(path).and_then(|f| Checker::check(BufReader::new(f))) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Can't read trace {}: {}", path, e);
+            return 2;
+        }
+    };
+    println!("{}", report);
+    if report.passed() {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(trace: &str) -> Report {
+        Checker::check(trace.as_bytes()).unwrap()
+    }
+
+    /// A tank loads at the mine and supplies a station in two portions
+    const ROUND: &str = "
+/mq-v0 load
+/mq-m0 request 0 20
+/mq-v0 fuel 12.5
+/mq-v0 fuel 7.5
+/mq-v0 move
+/mq-dispatch idle 1 10 0.3
+/mq-v0 idle 1 10 0.3
+/mq-status tank 0 1 100 1 0
+/mq-v0 unload
+/mq-s1 delivery 0 5
+/mq-v0 unload
+/mq-s1 delivery 0 5
+/mq-v0 fuel 2
+/mq-s1 delivery 0 0
+/mq-v0 move
+";
+
+    #[test]
+    fn a_round_trip_passes() {
+        let report = check(ROUND);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.messages, 15);
+    }
+
+    #[test]
+    fn unload_without_a_station_fails() {
+        let report = check("/mq-v0 load\n/mq-m0 request 0 20\n/mq-v0 move\n/mq-v0 unload\n");
+        let violation = report.violation.unwrap();
+        assert_eq!(violation.line, 4);
+        assert_eq!(violation.msg, Msg::TankUnload);
+    }
+
+    #[test]
+    fn fuel_beyond_the_request_fails() {
+        let report = check("/mq-m0 request 0 10\n/mq-v0 fuel 6\n/mq-v0 fuel 6\n");
+        assert_eq!(report.violation.unwrap().line, 3);
+    }
+
+    #[test]
+    fn fuel_after_the_mine_let_the_tank_go_fails() {
+        let report = check("/mq-m0 request 0 10\n/mq-v0 fuel 4\n/mq-v0 move\n/mq-v0 fuel 4\n");
+        assert_eq!(report.violation.unwrap().line, 4);
+    }
+
+    #[test]
+    fn delivery_to_a_station_which_did_not_ask_fails() {
+        let report = check(&ROUND.replace("/mq-s1 delivery 0 5", "/mq-s2 delivery 0 5"));
+        let violation = report.violation.unwrap();
+        assert_eq!(violation.queue, "/mq-s2");
+        assert_eq!(violation.msg, Msg::Delivery(0, 5.0));
+    }
+
+    #[test]
+    fn the_station_asks_again_after_the_tank_left() {
+        let report = check(&format!("{}/mq-s1 delivery 1 5\n", ROUND));
+        assert_eq!(report.violation.unwrap().line, 17);
+    }
+
+    #[test]
+    fn unload_with_a_broadcast_offer_before_arriving_fails() {
+        let report = check(
+            "/mq-v0 load
+/mq-status tank 0 2 0 -1 0
+/mq-m0 request 0 20
+/mq-dispatch idle 1 10 0.3
+/mq-v0 idle 1 10 0.3
+/mq-v0 unload
+",
+        );
+        let violation = report.violation.unwrap();
+        assert_eq!(violation.line, 6);
+        assert_eq!(violation.msg, Msg::TankUnload);
+    }
+
+    /// A long-haul tank supplies the depot while a local tank from
+    /// the depot heads for the station with the same index
+    const DEPOTS: &str = "
+/mq-m0 request 1 60
+/mq-v1 fuel 60
+/mq-v1 move
+/mq-depot-dispatch idle 0 10 0
+/mq-v1 idle 0 10 0
+/mq-status tank 1 1 100 0 0
+/mq-d0 request 0 20
+/mq-v0 fuel 20
+/mq-v0 move
+/mq-dispatch idle 0 1 0.3
+/mq-v0 idle 0 1 0.3
+/mq-status tank 0 1 100 0 0
+/mq-v1 unload
+/mq-d0 delivery 1 15
+/mq-v1 unload
+/mq-d0 delivery 1 0
+/mq-v1 move
+/mq-v0 unload
+/mq-s0 delivery 0 5
+/mq-v0 unload
+/mq-s0 delivery 0 0
+/mq-v0 move
+";
+
+    #[test]
+    fn leaving_a_depot_keeps_the_station_waiting() {
+        let report = check(DEPOTS);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.messages, 22);
+    }
+
+    #[test]
+    fn a_malformed_line_is_a_read_error() {
+        let err = Checker::check("/mq-v0 load\n/mq-v0 fuel lots\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2:"));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::demand::DemandProfile;
+use crate::map::Node;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+
+/// Scenario file format: one node per line, `<kind> key=value ...`,
+/// blank lines and lines starting with `#` are ignored.
+///
+/// ```text
+/// # two mines, the second one is slower but larger
+/// mine capacity=200 production=2
+/// mine capacity=300 production=1 reserve=2000 falloff=2 bays=2 transfer_rate=40
+/// station burn=0.3 low=30 high=90
+/// station capacity=200 fuel=50 max_burn=2 efficiency=1.5
+/// station mtbf=120 repair=10 breakdowns=30:10,90:5
+/// depot capacity=300 fuel=100 low=150
+/// station profile=sine:0.3,0.2,60 x=10 y=20
+/// grid demand=sine:0.8,0.3,120
+/// fleet tanks=3 haulers=1 stops=2 consumption=0.02
+/// road from=m0 to=d0 via=-20:0,0:10
+/// road from=d0 to=s1 length=40 capacity=2
+/// ```
+///
+/// See `DemandProfile::parse` for the station profiles and the grid demand.
+/// Without a `grid` line the demand is the power of the initial burn rates.
+/// `x` and `y` place a node on the map in km from the window center,
+/// without `road` lines every mine has a straight road to every station,
+/// or to every depot and every depot to every station.
+/// The tanks take the shortest way over the roads, a road `length` in km
+/// overrides the one of its shape and `capacity` limits the tanks on it at once.
+/// With depots the `haulers` carry fuel from the mines to the depots
+/// and the `tanks` from the depots to the stations.
+pub struct Scenario {
+    pub mines: Vec<MineConfig>,
+    pub stations: Vec<StationConfig>,
+    pub depots: Vec<DepotConfig>,
+    /// Total power demand in MW
+    pub demand: DemandProfile,
+    pub fleet: FleetConfig,
+    pub roads: Vec<RoadConfig>,
+    /// Keep the mine stock in shared memory (`--shm`)
+    pub mine_shm: bool,
+    /// Run each station in a forked process (`--station-procs`)
+    pub station_procs: bool,
+    /// The player drives the only local tank (`--player`)
+    pub player: bool,
+    /// The local tanks are run by an external process (`--external-tank`)
+    pub external_tank: bool,
+    /// File to record the message stream to (`--trace=FILE`)
+    pub trace: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MineConfig {
+    pub capacity: f32,
+    pub production: f32,
+    /// Total fuel the mine can ever produce, unlimited if `None`
+    pub reserve: Option<f32>,
+    /// Exponent of the production falloff as the reserve depletes
+    pub falloff: f32,
+    /// Number of vehicles which can load at once
+    pub bays: usize,
+    /// Loading speed of a bay, fuel units per second
+    pub transfer_rate: f32,
+    /// Map position in km, laid out by index if `None`
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+impl Default for MineConfig {
+    fn default() -> Self {
+        MineConfig {
+            capacity: 200.0, // 2x station's capacity
+            production: 2.0,
+            reserve: None,
+            falloff: 1.0,
+            bays: 1,
+            transfer_rate: 80.0,
+            x: None,
+            y: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DepotConfig {
+    pub capacity: f32,
+    /// Fuel at the start of the simulation
+    pub fuel: f32,
+    /// Ask the long-haul tanks for fuel at this level
+    pub low: f32,
+    /// Loading speed, fuel units per second
+    pub transfer_rate: f32,
+    /// Map position in km, laid out by index if `None`
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+impl Default for DepotConfig {
+    fn default() -> Self {
+        DepotConfig {
+            capacity: 300.0,
+            fuel: 100.0,
+            low: 150.0,
+            transfer_rate: 80.0,
+            x: None,
+            y: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FleetConfig {
+    pub tanks: usize,
+    /// Long-haul tanks between the mines and the depots, unused without depots
+    pub haulers: usize,
+    /// Stations a tank can split one load between
+    pub stops: usize,
+    /// Fuel a tank burns per world unit (km) of the road
+    pub consumption: f32,
+}
+
+impl Default for FleetConfig {
+    fn default() -> Self {
+        FleetConfig {
+            tanks: 1,
+            haulers: 1,
+            stops: 1,
+            consumption: 0.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StationConfig {
+    pub capacity: f32,
+    /// Fuel at the start of the simulation
+    pub fuel: f32,
+    /// Initial burn rate, or the rate for the manual override of a profile
+    pub burn: f32,
+    /// Upper bound of the burn rate, both manual and from the profile
+    pub max_burn: f32,
+    /// Power produced per unit of burned fuel
+    pub efficiency: f32,
+    pub profile: Option<DemandProfile>,
+    /// Ask the tank for fuel at this level
+    pub low: f32,
+    /// Stop accepting deliveries at this level
+    pub high: f32,
+    /// Mean time between random failures in seconds, never fails if `None`
+    pub mtbf: Option<f32>,
+    /// Seconds to repair after a random failure
+    pub repair: f32,
+    /// Scheduled failures as `(start, duration)` in seconds
+    pub breakdowns: Vec<(f32, f32)>,
+    /// Map position in km, laid out by index if `None`
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+}
+
+impl Default for StationConfig {
+    fn default() -> Self {
+        StationConfig {
+            capacity: 100.0,
+            fuel: 10.0,
+            burn: 0.2,
+            max_burn: 1.0,
+            efficiency: 1.0,
+            profile: None,
+            low: 20.0,
+            high: 100.0,
+            mtbf: None,
+            repair: 10.0,
+            breakdowns: Vec::new(),
+            x: None,
+            y: None,
+        }
+    }
+}
+
+/// A road between two nodes through the `via` points in km
+#[derive(Clone, Debug)]
+pub struct RoadConfig {
+    pub from: Node,
+    pub to: Node,
+    pub via: Vec<(f32, f32)>,
+    /// Length for the route search, the length of the shape if `None`
+    pub length: Option<f32>,
+    /// Tanks on the road at once, unlimited if `None`
+    pub capacity: Option<usize>,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        let stations = vec![StationConfig::default(); 4];
+        Scenario {
+            mines: vec![MineConfig::default()],
+            demand: Self::initial_power(&stations),
+            stations,
+            depots: Vec::new(),
+            fleet: FleetConfig::default(),
+            roads: Vec::new(),
+            mine_shm: false,
+            station_procs: false,
+            player: false,
+            external_tank: false,
+            trace: None,
+        }
+    }
+}
+
+struct Params<'a> {
+    line: usize,
+    kind: &'a str,
+    values: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> Params<'a> {
+    fn parse(line: usize, text: &'a str) -> Result<Self, String> {
+        let mut words = text.split_whitespace();
+        let kind = words.next().unwrap_or_default();
+        let mut values = HashMap::new();
+        for word in words {
+            let mut kv = word.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => values.insert(k, v),
+                _ => return Err(format!("line {}: expected key=value, got '{}'", line, word)),
+            };
+        }
+        Ok(Params { line, kind, values })
+    }
+
+    fn get<T: FromStr>(&mut self, key: &str, default: T) -> Result<T, String> {
+        Ok(self.get_opt(key)?.unwrap_or(default))
+    }
+
+    fn get_opt<T: FromStr>(&mut self, key: &str) -> Result<Option<T>, String> {
+        match self.values.remove(key) {
+            Some(v) => v
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("line {}: can't parse {}={}", self.line, key, v)),
+            None => Ok(None),
+        }
+    }
+
+    /// A map position in km, which must be a finite number
+    fn coord(&mut self, key: &str) -> Result<Option<f32>, String> {
+        match self.get_opt::<f32>(key)? {
+            Some(v) if !v.is_finite() => Err(format!(
+                "line {}: {} must be a finite number",
+                self.line, key
+            )),
+            v => Ok(v),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self.values.keys().next() {
+            Some(key) => Err(format!(
+                "line {}: unknown {} parameter '{}'",
+                self.line, self.kind, key
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Scenario {
+    /// Load the scenario from the command line: an optional scenario path
+    /// and `--flags` which switch the simulation modes
+    pub fn from_args() -> Self {
+        let (flags, paths): (Vec<String>, Vec<String>) =
+            env::args().skip(1).partition(|a| a.starts_with("--"));
+        let mut scenario = match paths.first() {
+            Some(path) => match Self::load(path) {
+                Ok(s) => {
+                    info!("Loaded scenario {}", path);
+                    s
+                }
+                Err(e) => {
+                    error!("Can't load scenario {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            },
+            None => Self::default(),
+        };
+        for flag in flags {
+            match flag.as_str() {
+                "--shm" => scenario.mine_shm = true,
+                "--station-procs" => scenario.station_procs = true,
+                "--player" => scenario.player = true,
+                "--external-tank" => scenario.external_tank = true,
+                flag if flag.starts_with("--trace=") => {
+                    scenario.trace = Some(flag["--trace=".len()..].to_string())
+                }
+                _ => {
+                    error!("Unknown flag {}", flag);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if scenario.player && scenario.external_tank {
+            error!("The player can't drive an external tank");
+            std::process::exit(1);
+        }
+        scenario
+    }
+
+    /// Number of tanks, the long-haul ones come after the local ones
+    pub fn vehicles(&self) -> usize {
+        if self.depots.is_empty() {
+            self.fleet.tanks
+        } else {
+            self.fleet.tanks + self.fleet.haulers
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::parse(&text)
+    }
+
+    /// Parse `a:b,c:d,...`
+    fn pairs(text: &str) -> Option<Vec<(f32, f32)>> {
+        text.split(',')
+            .map(|b| {
+                let mut b = b.splitn(2, ':');
+                match (b.next()?.parse::<f32>(), b.next()?.parse::<f32>()) {
+                    (Ok(a), Ok(b)) if a.is_finite() && b.is_finite() => Some((a, b)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// A finite number above zero, NaN passes any comparison
+    fn positive(x: f32) -> bool {
+        x.is_finite() && x > 0.0
+    }
+
+    fn initial_power(stations: &[StationConfig]) -> DemandProfile {
+        // a station burns no more than its max_burn
+        let power = stations
+            .iter()
+            .map(|s| f32::min(s.burn, s.max_burn) * s.efficiency);
+        DemandProfile::Constant(power.sum())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut mines = Vec::new();
+        let mut stations = Vec::new();
+        let mut depots = Vec::new();
+        let mut demand = None;
+        let mut fleet = FleetConfig::default();
+        let mut roads = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut p = Params::parse(n + 1, line)?;
+            match p.kind {
+                "mine" => {
+                    let d = MineConfig::default();
+                    let mine = MineConfig {
+                        capacity: p.get("capacity", d.capacity)?,
+                        production: p.get("production", d.production)?,
+                        reserve: p.get_opt("reserve")?,
+                        falloff: p.get("falloff", d.falloff)?,
+                        bays: p.get("bays", d.bays)?,
+                        transfer_rate: p.get("transfer_rate", d.transfer_rate)?,
+                        x: p.coord("x")?,
+                        y: p.coord("y")?,
+                    };
+                    if mine.bays == 0 {
+                        return Err(format!("line {}: a mine needs at least one bay", n + 1));
+                    }
+                    if !Self::positive(mine.transfer_rate) {
+                        return Err(format!("line {}: transfer_rate must be positive", n + 1));
+                    }
+                    if !mine.reserve.is_none_or(Self::positive) {
+                        return Err(format!("line {}: reserve must be positive", n + 1));
+                    }
+                    if !mine.falloff.is_finite() || mine.falloff < 0.0 {
+                        return Err(format!("line {}: falloff must not be negative", n + 1));
+                    }
+                    mines.push(mine);
+                }
+                "station" => {
+                    let d = StationConfig::default();
+                    let profile = match p.get_opt::<String>("profile")? {
+                        Some(text) => Some(
+                            DemandProfile::parse(&text)
+                                .map_err(|e| format!("line {}: {}", n + 1, e))?,
+                        ),
+                        None => None,
+                    };
+                    let breakdowns = match p.get_opt::<String>("breakdowns")? {
+                        Some(text) => Self::pairs(&text).ok_or_else(|| {
+                            format!("line {}: expected start:duration,...", n + 1)
+                        })?,
+                        None => d.breakdowns,
+                    };
+                    let capacity = p.get("capacity", d.capacity)?;
+                    let station = StationConfig {
+                        capacity,
+                        fuel: p.get("fuel", d.fuel)?,
+                        burn: p.get("burn", d.burn)?,
+                        max_burn: p.get("max_burn", d.max_burn)?,
+                        efficiency: p.get("efficiency", d.efficiency)?,
+                        profile,
+                        low: p.get("low", d.low)?,
+                        high: p.get("high", capacity)?,
+                        mtbf: p.get_opt("mtbf")?,
+                        repair: p.get("repair", d.repair)?,
+                        breakdowns,
+                        x: p.coord("x")?,
+                        y: p.coord("y")?,
+                    };
+                    let positive = [station.capacity, station.max_burn, station.efficiency];
+                    if !positive.iter().all(|x| Self::positive(*x)) {
+                        return Err(format!(
+                            "line {}: capacity, max_burn and efficiency must be positive",
+                            n + 1
+                        ));
+                    }
+                    let levels = [station.fuel, station.burn, station.low, station.high];
+                    if levels.iter().any(|x| !x.is_finite()) {
+                        return Err(format!(
+                            "line {}: fuel, burn, low and high must be finite numbers",
+                            n + 1
+                        ));
+                    }
+                    if station.fuel < 0.0 || station.fuel > station.capacity {
+                        return Err(format!("line {}: fuel must fit the capacity", n + 1));
+                    }
+                    if station.low >= station.high {
+                        return Err(format!("line {}: low must be below high", n + 1));
+                    }
+                    stations.push(station);
+                }
+                "depot" => {
+                    let d = DepotConfig::default();
+                    let depot = DepotConfig {
+                        capacity: p.get("capacity", d.capacity)?,
+                        fuel: p.get("fuel", d.fuel)?,
+                        low: p.get("low", d.low)?,
+                        transfer_rate: p.get("transfer_rate", d.transfer_rate)?,
+                        x: p.coord("x")?,
+                        y: p.coord("y")?,
+                    };
+                    if !Self::positive(depot.capacity) || !Self::positive(depot.transfer_rate) {
+                        return Err(format!(
+                            "line {}: capacity and transfer_rate must be positive",
+                            n + 1
+                        ));
+                    }
+                    if !depot.fuel.is_finite() || depot.fuel < 0.0 || depot.fuel > depot.capacity {
+                        return Err(format!("line {}: fuel must fit the capacity", n + 1));
+                    }
+                    if !depot.low.is_finite() || depot.low >= depot.capacity {
+                        return Err(format!("line {}: low must be below the capacity", n + 1));
+                    }
+                    depots.push(depot);
+                }
+                "grid" => {
+                    if demand.is_some() {
+                        return Err(format!("line {}: the grid is already defined", n + 1));
+                    }
+                    let text = p
+                        .get_opt::<String>("demand")?
+                        .ok_or_else(|| format!("line {}: the grid needs a demand", n + 1))?;
+                    demand = Some(
+                        DemandProfile::parse(&text)
+                            .map_err(|e| format!("line {}: {}", n + 1, e))?,
+                    );
+                }
+                "fleet" => {
+                    fleet = FleetConfig {
+                        tanks: p.get("tanks", fleet.tanks)?,
+                        haulers: p.get("haulers", fleet.haulers)?,
+                        stops: p.get("stops", fleet.stops)?,
+                        consumption: p.get("consumption", fleet.consumption)?,
+                    };
+                    if fleet.tanks == 0 || fleet.stops == 0 {
+                        return Err(format!(
+                            "line {}: the fleet needs at least one tank and one stop",
+                            n + 1
+                        ));
+                    }
+                }
+                "road" => {
+                    let mut node = |key: &str| {
+                        p.get_opt::<String>(key)?
+                            .ok_or_else(|| format!("line {}: the road needs {}", n + 1, key))?
+                            .parse::<Node>()
+                            .map_err(|e| format!("line {}: {}", n + 1, e))
+                    };
+                    let from = node("from")?;
+                    let to = node("to")?;
+                    let via = match p.get_opt::<String>("via")? {
+                        Some(text) => Self::pairs(&text)
+                            .ok_or_else(|| format!("line {}: expected x:y,...", n + 1))?,
+                        None => Vec::new(),
+                    };
+                    let road = RoadConfig {
+                        from,
+                        to,
+                        via,
+                        length: p.get_opt("length")?,
+                        capacity: p.get_opt("capacity")?,
+                    };
+                    // NaN passes any comparison
+                    let bad_length = matches!(road.length, Some(l) if !l.is_finite() || l <= 0.0);
+                    if bad_length || road.capacity == Some(0) {
+                        return Err(format!(
+                            "line {}: length and capacity must be positive",
+                            n + 1
+                        ));
+                    }
+                    roads.push((n + 1, road));
+                }
+                kind => return Err(format!("line {}: unknown node kind '{}'", n + 1, kind)),
+            }
+            p.finish()?;
+        }
+        let d = Scenario::default();
+        if mines.is_empty() {
+            mines = d.mines;
+        }
+        if stations.is_empty() {
+            stations = d.stations;
+        }
+        for (line, road) in roads.iter() {
+            for node in [road.from, road.to].iter() {
+                let exists = match *node {
+                    Node::Mine(idx) => idx < mines.len(),
+                    Node::Station(idx) => idx < stations.len(),
+                    Node::Depot(idx) => idx < depots.len(),
+                };
+                if !exists {
+                    return Err(format!("line {}: no such node {}", line, node));
+                }
+            }
+        }
+        if !depots.is_empty() && fleet.haulers == 0 {
+            return Err("the depots need at least one hauler".to_string());
+        }
+        Ok(Scenario {
+            demand: demand.unwrap_or_else(|| Self::initial_power(&stations)),
+            depots,
+            fleet,
+            roads: roads.into_iter().map(|(_, road)| road).collect(),
+            mines,
+            stations,
+            ..Default::default()
+        })
+    }
+}
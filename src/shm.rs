@@ -0,0 +1,135 @@
+use nix::fcntl::OFlag;
+use nix::libc;
+use nix::sys::mman::{mmap, munmap, shm_open, shm_unlink, MapFlags, ProtFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, ftruncate};
+use std::ffi::CString;
+use std::ops::{Deref, DerefMut};
+use std::{io, mem, ptr};
+
+#[repr(C)]
+struct Segment<T> {
+    waits: u64,
+    value: T,
+}
+
+/// A value kept in a POSIX shared memory segment and guarded by a named
+/// POSIX semaphore, so it can be shared with forked processes.
+pub struct ShmCell<T: Copy> {
+    seg: *mut Segment<T>,
+    sem: *mut libc::sem_t,
+    name: String,
+}
+
+// the segment is only accessed while holding the semaphore
+unsafe impl<T: Copy + Send> Send for ShmCell<T> {}
+unsafe impl<T: Copy + Send> Sync for ShmCell<T> {}
+
+impl<T: Copy> ShmCell<T> {
+    /// Create (or recreate) the segment `name` and the semaphore `name-lock`
+    pub fn create(name: &str, value: T) -> Self {
+        let _ = shm_unlink(name);
+        let fd = shm_open(
+            name,
+            OFlag::O_CREAT | OFlag::O_RDWR,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )
+        .expect("Open shared memory segment");
+        let size = mem::size_of::<Segment<T>>();
+        ftruncate(fd, size as libc::off_t).expect("Resize shared memory segment");
+        let seg = unsafe {
+            mmap(
+                ptr::null_mut(),
+                size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )
+        }
+        .expect("Map shared memory segment") as *mut Segment<T>;
+        close(fd).expect("Close shared memory descriptor");
+        unsafe { ptr::write(seg, Segment { waits: 0, value }) };
+
+        let sem_name = Self::sem_name(name);
+        let sem = unsafe {
+            libc::sem_unlink(sem_name.as_ptr());
+            libc::sem_open(
+                sem_name.as_ptr(),
+                libc::O_CREAT,
+                0o600 as libc::c_uint,
+                1 as libc::c_uint,
+            )
+        };
+        if sem == libc::SEM_FAILED {
+            panic!("Open semaphore: {}", io::Error::last_os_error());
+        }
+        info!("Created shared memory segment {} of {} bytes", name, size);
+        ShmCell {
+            seg,
+            sem,
+            name: name.to_string(),
+        }
+    }
+
+    fn sem_name(name: &str) -> CString {
+        CString::new(format!("{}-lock", name)).expect("Semaphore name")
+    }
+
+    /// Wait for the semaphore, counting the times it was already taken
+    pub fn lock(&self) -> ShmGuard<'_, T> {
+        unsafe {
+            if libc::sem_trywait(self.sem) != 0 {
+                while libc::sem_wait(self.sem) != 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        panic!("Wait semaphore: {}", err);
+                    }
+                }
+                (*self.seg).waits += 1;
+            }
+        }
+        ShmGuard { cell: self }
+    }
+}
+
+impl<T: Copy> Drop for ShmCell<T> {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.seg as *mut libc::c_void, mem::size_of::<Segment<T>>());
+            libc::sem_close(self.sem);
+            libc::sem_unlink(Self::sem_name(&self.name).as_ptr());
+        }
+        let _ = shm_unlink(self.name.as_str());
+    }
+}
+
+pub struct ShmGuard<'a, T: Copy> {
+    cell: &'a ShmCell<T>,
+}
+
+impl<'a, T: Copy> ShmGuard<'a, T> {
+    /// How many times a process had to wait for the semaphore
+    pub fn waits(&self) -> u64 {
+        unsafe { (*self.cell.seg).waits }
+    }
+}
+
+impl<'a, T: Copy> Deref for ShmGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &(*self.cell.seg).value }
+    }
+}
+
+impl<'a, T: Copy> DerefMut for ShmGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut (*self.cell.seg).value }
+    }
+}
+
+impl<'a, T: Copy> Drop for ShmGuard<'a, T> {
+    fn drop(&mut self) {
+        unsafe { libc::sem_post(self.cell.sem) };
+    }
+}
@@ -1,141 +1,356 @@
-use crate::posixmq::{Msg, PMQ, STATION_QUEUE_PREFIX, VEHICLE_QUEUE};
+use crate::demand::DemandProfile;
+use crate::ledger;
+use crate::posixmq::{self, Msg, DISPATCH_QUEUE, PMQ, STATION_QUEUE_PREFIX, STATUS_QUEUE};
+use crate::scenario::StationConfig;
+use nannou::prelude::*;
+use nannou::rand::random_f32;
 use nannou::ui::prelude::*;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use nix::unistd::{fork, getpid, getppid, ForkResult, Pid};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::{process, thread};
+
+/// Gauge height in pixels of the station with the largest capacity
+const GAUGE_HEIGHT: f64 = 100.0;
+
+#[derive(Copy, Clone, Debug)]
+struct Burn {
+    rate: f32,
+    /// Set by the slider, overrides the demand profile
+    manual: bool,
+}
+
+/// Station state as last reported over the status queue
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StationStatus {
+    pub fuel: f32,
+    pub rate: f32,
+    pub pid: i32,
+    pub broken: bool,
+}
 
 pub struct Station {
     id: widget::Id,
     id_burning: widget::Id,
     idx: usize,
-    fuel: Arc<Mutex<f32>>,
-    speed: Arc<Mutex<f32>>,
-    speed_update: f32,
+    status: StationStatus,
+    manual: bool,
+    has_profile: bool,
+    // control message which did not fit into the full station queue
+    pending: Option<Msg>,
+    q: PMQ,
     label: String,
     capacity: f32,
+    max_burn: f32,
+    efficiency: f32,
     height: f64,
 }
 
 impl Station {
-    pub fn new(idx: usize, id: widget::Id, id_burning: widget::Id) -> Self {
-        let fuel = Arc::new(Mutex::new(10.));
-        let speed_update = 0.2;
-        let speed = Arc::new(Mutex::new(speed_update));
-        Station {
+    /// The gauge and the slider of a station started by `spawn`, the gauges
+    /// are as tall as the capacity against the `largest` capacity
+    pub fn new(
+        idx: usize,
+        id: widget::Id,
+        id_burning: widget::Id,
+        config: &StationConfig,
+        largest: f32,
+        process: bool,
+    ) -> Self {
+        let station = Station {
             id,
             id_burning,
             idx,
-            fuel,
-            speed,
-            speed_update,
+            status: StationStatus {
+                fuel: config.fuel,
+                rate: f32::min(config.burn, config.max_burn),
+                pid: 0,
+                broken: false,
+            },
+            manual: config.profile.is_none(),
+            has_profile: config.profile.is_some(),
+            pending: None,
+            q: PMQ::open(Self::queue_name(idx).as_ref()).nonblocking(),
             label: "0".to_string(),
-            capacity: 100.0,
-            height: 100.0,
+            capacity: config.capacity,
+            max_burn: config.max_burn,
+            efficiency: config.efficiency,
+            height: (config.capacity / largest) as f64 * GAUGE_HEIGHT,
+        };
+        Self::spawn(idx, config, process);
+        station
+    }
+
+    /// Run the station in a thread, or in a forked process if `process` is set,
+    /// either way it is driven only through its queue and reports to `STATUS_QUEUE`
+    pub fn spawn(idx: usize, config: &StationConfig, process: bool) {
+        let mut config = config.clone();
+        config.high = f32::min(config.high, config.capacity);
+        let fuel = config.fuel;
+        if process {
+            Self::fork(idx, fuel, config);
+        } else {
+            thread::spawn(move || Self::run(idx, fuel, config, None));
+        }
+    }
+
+    fn queue_name(idx: usize) -> String {
+        format!("{}{}", STATION_QUEUE_PREFIX, idx)
+    }
+
+    pub fn set_status(&mut self, status: StationStatus) {
+        let rate = self.status.rate;
+        self.status = status;
+        if self.pending.is_some() {
+            // keep showing the rate which is not delivered yet
+            self.status.rate = rate;
         }
-        .launch()
     }
 
-    pub fn update(&mut self, ui: &mut UiCell) {
-        let speed = self.build_control(ui, self.speed_update);
-        if self.speed_update != speed {
-            // avoid excess locks
-            self.speed_update = speed;
-            *self.speed.lock().unwrap() = speed;
+    /// Draw the gauge with its bottom right corner at the map `node` on the screen
+    pub fn update(&mut self, ui: &mut UiCell, node: Point2) {
+        if let Some(msg) = self.pending.take() {
+            self.send(msg);
         }
-        let f: f32 = *self.fuel.lock().unwrap();
-        self.label = format!("{:.0}", f);
+        let speed = self.build_control(ui, node);
+        if self.status.rate != speed {
+            if !self.manual {
+                info!("Station #{} burn rate is set manually", self.idx);
+                self.manual = true;
+            }
+            self.status.rate = speed;
+            self.send(Msg::BurnRate(speed));
+        }
+        let f = self.status.fuel;
+        self.label = format!("{:.0}\n{:.1}\n{}", f, self.power(), self.status.pid);
+        let (r, g, b) = if self.status.broken {
+            (0.9, 0.2, 0.2)
+        } else {
+            (0.3, 0.8, 0.3)
+        };
         widget::Slider::new(f, 0., self.capacity)
             .label(&self.label)
             .enabled(false)
             .w_h(self.height * 0.5, self.height)
-            .label_font_size(15)
-            .rgb(0.3, 0.8, 0.3)
+            .label_font_size(12)
+            .rgb(r, g, b)
             .label_rgb(1.0, 1.0, 1.0)
             .border(0.1)
             .left(5.0)
+            .align_bottom_of(self.id_burning)
             .set(self.id, ui);
     }
 
-    pub fn build_control(&mut self, ui: &mut UiCell, speed: f32) -> f32 {
-        let mut w = widget::Slider::new(speed, 0.0, 1.0)
+    fn send(&mut self, msg: Msg) {
+        if let Err(e) = self.q.send(msg.clone()) {
+            trace!(
+                "Station #{} queue is busy: {:?}, retry {:?}",
+                self.idx,
+                e,
+                msg
+            );
+            self.pending = Some(msg);
+        }
+    }
+
+    /// Power output at the current burn rate
+    pub fn power(&self) -> f32 {
+        if self.status.broken || self.status.fuel <= 0.0 {
+            return 0.0;
+        }
+        self.status.rate * self.efficiency
+    }
+
+    /// The station stands still for the lack of fuel
+    pub fn is_out_of_fuel(&self) -> bool {
+        self.status.fuel <= 0.0
+    }
+
+    pub fn toggle_broken(&mut self) {
+        self.send(Msg::ToggleBroken);
+    }
+
+    /// Follow the demand profile again after a manual override
+    pub fn resume_profile(&mut self) {
+        if self.has_profile {
+            self.manual = false;
+            self.send(Msg::FollowProfile);
+        }
+    }
+
+    /// Burn rate slider right of the `node`, the gauge is left of it
+    fn build_control(&mut self, ui: &mut UiCell, node: Point2) -> f32 {
+        let mut w = widget::Slider::new(self.status.rate, 0.0, self.max_burn)
             .w_h(10.0, 50.0)
-            .rgb(1.0, 1.0, 0.3)
+            .x_y(node.x as f64 + 10.0, node.y as f64 + 25.0)
             .border(0.0);
-        if self.idx == 0 {
-            w = w.top_right_with_margin(20.0);
+        w = if self.manual {
+            w.rgb(1.0, 1.0, 0.3)
         } else {
-            w = w.left(20.0);
+            w.rgb(0.3, 0.7, 1.0)
+        };
+        w.set(self.id_burning, ui).unwrap_or(self.status.rate)
+    }
+
+    fn fork(idx: usize, fuel: f32, config: StationConfig) {
+        let parent_pid = getpid();
+        match fork() {
+            Ok(ForkResult::Parent { child, .. }) => {
+                info!("Station #{} runs in process {}", idx, child);
+            }
+            Ok(ForkResult::Child) => Self::run(idx, fuel, config, Some(parent_pid)),
+            Err(_) => panic!("Fork failed"),
         }
-        w.set(self.id_burning, ui).unwrap_or(speed)
     }
 
-    fn launch(self) -> Self {
-        let idx = self.idx;
-        let f = self.fuel.clone();
-        let s = self.speed.clone();
-        let capacity = self.capacity;
-        let mq_v = PMQ::open(VEHICLE_QUEUE);
-        let q_name = format!("{}{}", STATION_QUEUE_PREFIX, idx);
-        let q = PMQ::open(q_name.as_ref()).nonblocking();
+    fn run(idx: usize, mut fuel: f32, config: StationConfig, parent: Option<Pid>) -> ! {
+        let (low, high, max_burn) = (config.low, config.high, config.max_burn);
+        let profile: Option<DemandProfile> = config.profile;
+        let mut burn = Burn {
+            rate: f32::min(config.burn, max_burn),
+            manual: profile.is_none(),
+        };
+        let mq_dispatch = PMQ::open(DISPATCH_QUEUE);
+        let mut mq_v: HashMap<usize, PMQ> = HashMap::new();
+        let mq_status = PMQ::open(STATUS_QUEUE).nonblocking();
+        let q = PMQ::open(Self::queue_name(idx).as_ref()).nonblocking();
         let delay = Duration::from_millis(100);
+        let pid = getpid().as_raw();
+        let report = |fuel: f32, rate: f32, broken: bool| {
+            // the status is sent every tick, drop it if the UI is behind
+            let _ = mq_status.send(Msg::StationStatus(idx, fuel, rate, pid, broken));
+        };
 
-        thread::spawn(move || {
-            info!("Build station #{}", idx);
-            loop {
-                let msg = q.receive();
-                if let Ok(ref msg) = msg {
-                    trace!(
-                        "Station #{} receive msg: {:?}, current {}",
-                        idx,
-                        msg,
-                        *f.lock().unwrap()
-                    );
-                }
-                match msg {
-                    Ok(Msg::Fuel(amount)) => {
-                        if amount > 0.0 {
-                            // full station in blocking mode
-                            q.set_nonblocking(false);
-
-                            thread::sleep(delay / 2);
-                            let mut f = f.lock().unwrap();
-                            let update = *f + amount;
-                            let remain = f32::max(update - capacity, 0.0);
-                            *f = update - remain;
-                            if remain > 0.0 {
-                                info!("Set queue non blocking mode remain={}", remain);
-                                mq_v.send(Msg::Fuel(remain)).expect("Send remain tank fuel");
-                            } else {
-                                mq_v.send(Msg::TankUnload).expect("Send tank unload");
-                            }
+        info!("Build station #{}", idx);
+        ledger::produce(fuel);
+        let start = Instant::now();
+        let mut requested = false;
+        // simulation time of the repair, broken until toggled if infinite
+        let mut broken: Option<f32> = None;
+        let mut last_tick = 0.0;
+        loop {
+            let t = start.elapsed();
+            let now = t.as_secs() as f32 + t.subsec_millis() as f32 / 1e3;
+            let msg = q.receive();
+            if let Ok(ref msg) = msg {
+                trace!("Station #{} receive msg: {:?}, current {}", idx, msg, fuel);
+            }
+            match msg {
+                Ok(Msg::Delivery(tank, amount)) => {
+                    let mq_v = mq_v
+                        .entry(tank)
+                        .or_insert_with(|| PMQ::open(posixmq::vehicle_queue(tank).as_ref()));
+                    if amount > 0.0 && broken.is_some() {
+                        info!("Station #{} is broken, reject {} fuel", idx, amount);
+                        mq_v.send(Msg::Rejected(idx, amount))
+                            .expect("Send rejected fuel");
+                        // the tank leaves without the closing delivery
+                        q.set_nonblocking(true);
+                        requested = false;
+                    } else if amount > 0.0 {
+                        // full station in blocking mode
+                        q.set_nonblocking(false);
+
+                        thread::sleep(delay / 2);
+                        let update = fuel + amount;
+                        let remain = f32::max(update - f32::max(high, fuel), 0.0);
+                        let before = fuel;
+                        fuel = update - remain;
+                        ledger::transfer(fuel - before, remain - amount);
+                        if remain > 0.0 {
+                            info!("Set queue non blocking mode remain={}", remain);
+                            mq_v.send(Msg::Fuel(remain)).expect("Send remain tank fuel");
                         } else {
-                            mq_v.send(Msg::TankMove)
-                                .expect("Send TankMove from station");
-                            info!("Set queue non blocking mode");
+                            mq_v.send(Msg::TankUnload).expect("Send tank unload");
+                        }
+                        report(fuel, burn.rate, false);
+                    } else {
+                        mq_v.send(Msg::TankMove)
+                            .expect("Send TankMove from station");
+                        info!("Set queue non blocking mode");
+                        q.set_nonblocking(true);
+                        // ask again if the delivery was not enough
+                        requested = false;
+                    }
+                }
+                Ok(Msg::BurnRate(rate)) => {
+                    burn = Burn {
+                        rate: f32::min(rate, max_burn),
+                        manual: true,
+                    }
+                }
+                Ok(Msg::FollowProfile) => burn.manual = profile.is_none(),
+                Ok(Msg::ToggleBroken) => {
+                    broken = match broken {
+                        Some(_) => {
+                            info!("Station #{} is repaired manually", idx);
+                            requested = false;
+                            None
+                        }
+                        None => {
+                            warn!("Station #{} is broken manually", idx);
+                            // keep burning and reporting if it breaks during a delivery
                             q.set_nonblocking(true);
+                            requested = false;
+                            Some(f32::INFINITY)
                         }
+                    };
+                    report(fuel, burn.rate, broken.is_some());
+                }
+                Ok(msg) => error!("Unsupported message for station: {:?}", msg),
+                Err(_) => {
+                    if let (false, Some(p)) = (burn.manual, &profile) {
+                        burn.rate = f32::min(p.at(now), max_burn);
                     }
-                    Ok(_) => unreachable!(),
-                    Err(_) => {
-                        if let Ok(mut f) = f.lock() {
-                            if let Ok(s) = s.lock() {
-                                if *f > 0.0 {
-                                    if *s > 0.0 {
-                                        trace!("Station #{} burned {:.3} fuel", idx, *s);
-                                        *f = f32::max(0., *f - *s);
-                                    }
-                                } else {
-                                    mq_v.send(Msg::IdleStation(idx)).expect("Send idle station");
-                                    info!("Set queue blocking mode");
-                                    q.set_nonblocking(false);
+                    match broken {
+                        Some(until) if now >= until => {
+                            info!("Station #{} is repaired", idx);
+                            broken = None;
+                            // a request sent while broken may have been dropped by the tank
+                            requested = false;
+                        }
+                        Some(_) => (),
+                        None => {
+                            let dt = now - last_tick;
+                            let scheduled = config
+                                .breakdowns
+                                .iter()
+                                .find(|(at, _)| *at > last_tick && *at <= now);
+                            if let Some((at, duration)) = scheduled {
+                                warn!("Station #{} breaks down for {}s", idx, duration);
+                                broken = Some(at + duration);
+                            } else if let Some(mtbf) = config.mtbf {
+                                if random_f32() < dt / mtbf {
+                                    warn!("Station #{} fails for {}s", idx, config.repair);
+                                    broken = Some(now + config.repair);
                                 }
                             }
                         }
-                        thread::sleep(delay);
                     }
+                    last_tick = now;
+                    if broken.is_none() && fuel > 0.0 && burn.rate > 0.0 {
+                        trace!("Station #{} burned {:.3} fuel", idx, burn.rate);
+                        let before = fuel;
+                        fuel = f32::max(0., fuel - burn.rate);
+                        ledger::burn(before - fuel);
+                    }
+                    if broken.is_none() && fuel <= low && !requested {
+                        info!("Station #{} is low on fuel: {:.1}", idx, fuel);
+                        mq_dispatch
+                            .send(Msg::IdleStation(idx, fuel, burn.rate))
+                            .expect("Send idle station");
+                        requested = true;
+                    }
+                    report(fuel, burn.rate, broken.is_some());
+                    if let Some(parent) = parent {
+                        if parent != getppid() {
+                            warn!("Parent pid exited, exit");
+                            process::exit(1);
+                        }
+                    }
+                    thread::sleep(delay);
                 }
             }
-        });
-        self
+        }
     }
 }
@@ -1,190 +1,382 @@
-use crate::posixmq::{Msg, MINE_QUEUE, PMQ, STATION_QUEUE_PREFIX, VEHICLE_QUEUE};
-use std::collections::HashMap;
+use crate::dispatch::{self, DispatchStrategy, MinePolicy, Positions, TankView};
+use crate::fleet::Coordinator;
+use crate::ledger;
+use crate::map::Node;
+use crate::mine::MineStore;
+pub use crate::msg::TankState;
+use crate::posixmq::{self, Msg, PMQ};
+use crate::protocol;
+use crate::tank_machine::{Event, Out, Planner, TankMachine};
+use nannou::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-const DEFAULT_SCORE: i32 = 100;
+// fuel a local tank carries, a long-haul one carries three times more
+const CAPACITY: f32 = 20.0;
 
-#[derive(Copy, Clone, Debug)]
-pub enum TankState {
-    Refill(f32),
-    Supply(f32),
-    Load(f32),
-    Unload(f32),
+/// Where a tank loads and where it delivers
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Role {
+    /// From the mines to the stations
+    Direct,
+    /// From the mines to the depots
+    LongHaul,
+    /// From the depots to the stations
+    Local,
+}
+
+impl Role {
+    /// The node a tank loads at by its index
+    pub fn source(self, idx: usize) -> Node {
+        match self {
+            Role::Direct | Role::LongHaul => Node::Mine(idx),
+            Role::Local => Node::Depot(idx),
+        }
+    }
+
+    /// The node a tank delivers to by its index
+    pub fn target(self, idx: usize) -> Node {
+        match self {
+            Role::Direct | Role::Local => Node::Station(idx),
+            Role::LongHaul => Node::Depot(idx),
+        }
+    }
+
+    pub fn capacity(self) -> f32 {
+        match self {
+            Role::LongHaul => CAPACITY * 3.0,
+            _ => CAPACITY,
+        }
+    }
+}
+
+/// Who picks the stations of a tank
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Control {
+    /// The dispatch strategy of the fleet
+    Auto,
+    /// The player drives the vehicle and picks the station by unloading at it
+    Player,
+    /// A process of its own serves the tank queue and publishes the tank state
+    External,
 }
 
 pub struct Tank {
-    supply_target: Arc<Mutex<Option<usize>>>,
-    capacity: f32,
-    state: Arc<Mutex<TankState>>,
+    idx: usize,
+    role: Role,
+    control: Control,
+    stops: usize,
+    mines: Vec<MineStore>,
+    targets: usize,
+    machine: Arc<Mutex<TankMachine>>,
+    strategy: Arc<Mutex<Box<dyn DispatchStrategy>>>,
+    mine_policy: Arc<Mutex<MinePolicy>>,
+    positions: Arc<Mutex<Positions>>,
+    coordinator: Arc<Mutex<Coordinator>>,
     q: PMQ,
 }
 
+/// Planner of a tank of the fleet: the strategy picks among the stations
+/// no other tank goes to
+struct FleetPlanner {
+    tank: usize,
+    control: Control,
+    stops: usize,
+    capacity: f32,
+    mines: Vec<MineStore>,
+    strategy: Arc<Mutex<Box<dyn DispatchStrategy>>>,
+    mine_policy: Arc<Mutex<MinePolicy>>,
+    positions: Arc<Mutex<Positions>>,
+    coordinator: Arc<Mutex<Coordinator>>,
+}
+
+impl Planner for FleetPlanner {
+    fn plan_route(&mut self, state: TankState, fuel: f32) -> Vec<usize> {
+        if self.control == Control::Player {
+            return Vec::new();
+        }
+        let positions = self.positions.lock().unwrap();
+        let view = TankView {
+            state,
+            fuel,
+            capacity: self.capacity,
+            position: positions.tank,
+        };
+        let mut strategy = self.strategy.lock().unwrap();
+        self.coordinator.lock().unwrap().route(
+            self.tank,
+            self.stops,
+            strategy.as_mut(),
+            &positions,
+            view,
+        )
+    }
+
+    fn choose_mine(&self, depleted: &HashSet<usize>) -> Option<usize> {
+        // the tank may have missed the news of a mine
+        let coordinator = self.coordinator.lock().unwrap();
+        let depleted = depleted | coordinator.depleted();
+        drop(coordinator);
+        let policy = *self.mine_policy.lock().unwrap();
+        match policy {
+            MinePolicy::Fullest => Tank::fullest_mine(&self.mines, &depleted),
+            MinePolicy::Nearest => {
+                let positions = self.positions.lock().unwrap();
+                Tank::nearest_mine(&self.mines, &positions, &depleted)
+                    .or_else(|| Tank::fullest_mine(&self.mines, &depleted))
+            }
+        }
+    }
+
+    fn claim(&mut self, station: usize) {
+        self.coordinator.lock().unwrap().claim(self.tank, station);
+    }
+}
+
 impl Tank {
-    pub fn new() -> Self {
+    /// A tank of the fleet visiting up to `stops` targets with one load,
+    /// `mines` are the stocks of the nodes it loads at, `targets` the number
+    /// of the nodes it delivers to
+    pub fn new(
+        idx: usize,
+        role: Role,
+        control: Control,
+        stops: usize,
+        mines: Vec<MineStore>,
+        targets: usize,
+        coordinator: Arc<Mutex<Coordinator>>,
+    ) -> Self {
+        let mine = Self::fullest_mine(&mines, &HashSet::new()).unwrap_or(0);
         let t = Tank {
-            supply_target: Arc::new(Mutex::new(None)),
-            capacity: 20.0,
-            state: Arc::new(Mutex::new(TankState::Refill(0.0))),
-            q: PMQ::open(VEHICLE_QUEUE),
+            idx,
+            role,
+            targets,
+            control,
+            stops,
+            mines,
+            machine: Arc::new(Mutex::new(TankMachine::new(idx, role.capacity(), mine))),
+            strategy: Arc::new(Mutex::new(Box::new(dispatch::EarliestEmpty))),
+            mine_policy: Arc::new(Mutex::new(MinePolicy::Fullest)),
+            positions: Arc::new(Mutex::new(Positions::default())),
+            coordinator,
+            q: PMQ::open(posixmq::vehicle_queue(idx).as_ref()),
         };
-        t.spawn_worker();
-        t.load();
+        if control == Control::External {
+            info!("Tank #{} waits for an external process on its queue", idx);
+        } else {
+            t.spawn_worker();
+            t.load();
+        }
         t
     }
 
+    /// Pick the not depleted mine with the largest stock, the first one wins a tie
+    fn fullest_mine(mines: &[MineStore], depleted: &HashSet<usize>) -> Option<usize> {
+        let mut best: Option<(usize, f32)> = None;
+        for (idx, mine) in mines.iter().enumerate() {
+            if depleted.contains(&idx) {
+                continue;
+            }
+            let level = mine.fuel();
+            match best {
+                Some((_, max)) if max >= level => (),
+                _ => best = Some((idx, level)),
+            }
+        }
+        best.map(|b| b.0)
+    }
+
+    /// Pick the not depleted mine closest to the tank, `None` without the positions
+    fn nearest_mine(
+        mines: &[MineStore],
+        positions: &Positions,
+        depleted: &HashSet<usize>,
+    ) -> Option<usize> {
+        let tank = positions.tank?;
+        if positions.sources.len() != mines.len() {
+            return None;
+        }
+        positions
+            .sources
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !depleted.contains(idx))
+            .map(|(idx, p)| (idx, tank.distance(*p)))
+            // the first one wins a tie
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(idx, _)| idx)
+    }
+
     fn spawn_worker(&self) {
-        let capacity = self.capacity;
-        let fourth = capacity * 0.25;
-        let state = self.state.clone();
-        let target = self.supply_target.clone();
+        let id = self.idx;
+        let role = self.role;
+        let targets = self.targets;
+        let machine = self.machine.clone();
+        let mut planner = FleetPlanner {
+            tank: id,
+            control: self.control,
+            stops: self.stops,
+            capacity: machine.lock().unwrap().capacity,
+            mines: self.mines.clone(),
+            strategy: self.strategy.clone(),
+            mine_policy: self.mine_policy.clone(),
+            positions: self.positions.clone(),
+            coordinator: self.coordinator.clone(),
+        };
 
         thread::spawn(move || {
-            info!("Employ vehicle worker");
-            let mq_v = PMQ::open(VEHICLE_QUEUE);
-            let mq_m = PMQ::open(MINE_QUEUE);
-
+            info!("Employ vehicle worker #{} {:?}", id, role);
+            let mq_v = PMQ::open(posixmq::vehicle_queue(id).as_ref());
+            let mq_m: Vec<PMQ> = (0..planner.mines.len())
+                .map(|idx| PMQ::open(role.source(idx).queue().as_ref()))
+                .collect();
             let mut mq_s: HashMap<usize, PMQ> = HashMap::new();
-            let mut idle_stations: HashMap<usize, i32> = HashMap::new();
-
-            let mut fuel = 0.0;
 
             loop {
-                let msg = mq_v.receive();
-                trace!("Tank receive msg: {:?}", msg);
-                match msg {
-                    Ok(Msg::Fuel(amount)) => {
-                        fuel += amount;
-                        let mut state = state.lock().unwrap();
-                        *state = match *state {
-                            TankState::Load(_) => TankState::Load(fuel / capacity * 100.0),
-                            TankState::Unload(_) => {
-                                let t = target.lock().unwrap();
-                                info!("Remain fuel {} from station {:?}", amount, *t);
-                                // send unload
-                                match *t {
-                                    Some(idx) => {
-                                        let s = idle_stations.remove(&idx);
-                                        info!("Remove station {:?} from idle stations", s);
-                                        // trigger TankMove
-                                        mq_s[&idx].send(Msg::Fuel(0.0)).expect(
-                                            format!("Send fuel to station {}", idx).as_ref(),
-                                        );
-                                    }
-                                    _ => unreachable!("Try unload to unknown station!"),
-                                };
-                                TankState::Unload(fuel / capacity * 100.0)
-                            }
-                            _ => {
-                                error!("Receive fuel from unexpected state: {:?}", *state);
-                                *state
-                            }
-                        }
-                    }
-                    Ok(Msg::TankLoad) => {
-                        mq_m.send(Msg::Fuel(capacity - fuel))
-                            .expect("Send fuel request");
-                        *state.lock().unwrap() = TankState::Load(fuel / capacity * 100.0);
-                    }
-                    Ok(Msg::TankUnload) => {
-                        let sub = f32::min(fuel, fourth);
-                        fuel = f32::max(fuel - sub, 0.0);
-                        *state.lock().unwrap() = TankState::Unload(fuel / capacity * 100.0);
-                        match *target.lock().unwrap() {
-                            Some(idx) => {
-                                if let Some(level) = idle_stations.get_mut(&idx) {
-                                    *level -= 1;
-                                }
-                                mq_s[&idx]
-                                    .send(Msg::Fuel(sub))
-                                    .expect(format!("Send fuel to station {}", idx).as_ref());
-                            }
-                            _ => unreachable!("Try unload to unknown station!"),
-                        }
+                let msg = match mq_v.receive() {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        error!("Vehicle queue receive error: {:?}", e);
+                        break;
                     }
-                    Ok(Msg::TankMove) => {
-                        let mut s = state.lock().unwrap();
-                        let mut t = target.lock().unwrap();
-                        match *s {
-                            TankState::Load(_) => {
-                                if fuel < fourth {
-                                    debug!(
-                                        "not enough fuel received: val={}, need={}",
-                                        fuel, fourth
-                                    );
-                                    mq_m.send(Msg::Fuel(capacity - fuel))
-                                        .expect("Send fuel request");
-                                } else {
-                                    *t = match *t {
-                                        Some(idx) => Some(idx),
-                                        None => {
-                                            let next = idle_stations
-                                                .iter()
-                                                .max_by(|a, b| (*a.1 as u32).cmp(&(*b.1 as u32)));
-                                            if let Some(idx) = next {
-                                                Some(*idx.0)
-                                            } else {
-                                                None
-                                            }
-                                        }
-                                    };
-                                    if t.is_some() {
-                                        *s = TankState::Supply(fuel / capacity * 100.0);
-                                    }
-                                    info!("Supply to station {:?}", *t);
-                                }
-                            }
-                            TankState::Unload(_) | TankState::Refill(_) | TankState::Supply(_) => {
-                                let next = idle_stations
-                                    .iter()
-                                    .max_by(|a, b| (*a.1 as u32).cmp(&(*b.1 as u32)));
-                                if let Some(idx) = next {
-                                    *t = Some(*idx.0);
-                                    if fuel == 0.0 {
-                                        *s = TankState::Refill(fuel / capacity * 100.0);
-                                    } else {
-                                        *s = TankState::Supply(fuel / capacity * 100.0);
-                                    }
-                                } else {
-                                    *t = None;
-                                    *s = TankState::Refill(fuel / capacity * 100.0);
-                                }
-                                trace!(
-                                    "Set next target to {:?} from {:?}, state={:?}",
-                                    next,
-                                    idle_stations,
-                                    *s
-                                );
-                            }
-                        }
+                };
+                trace!("Tank #{} receive msg: {:?}", id, msg);
+                let event = match Event::from_msg(msg.clone()) {
+                    Some(event) => event,
+                    None => {
+                        error!("Unsupported message for tank: {:?}", msg);
+                        continue;
                     }
-                    Ok(Msg::IdleStation(idx)) => {
-                        if !mq_s.contains_key(&idx) {
-                            let q_name = format!("{}{}", STATION_QUEUE_PREFIX, idx);
-                            mq_s.insert(idx, PMQ::open(q_name.as_ref()));
-                        }
-                        if *target.lock().unwrap() == None {
-                            *target.lock().unwrap() = Some(idx);
-                            // trigger TankMove
-                            mq_m.send(Msg::Fuel(0.0)).expect("Send fuel request");
-                        }
-                        idle_stations.insert(idx, DEFAULT_SCORE);
+                };
+                if let Event::Dispatch(idx) = event {
+                    if idx >= targets {
+                        warn!("Tank #{} is sent to unknown {:?}", id, role.target(idx));
+                        continue;
                     }
+                }
+                let step = {
+                    let mut m = machine.lock().unwrap();
+                    let fuel = m.fuel;
+                    m.step(event, &mut planner).map(|(next, out)| {
+                        let kept = next.fuel - fuel;
+                        // the state an external tank publishes, for the protocol trace
+                        let status = if (next.phase, next.target) != (m.phase, m.target) {
+                            Some(Msg::TankStatus(id, next.state(), next.target, next.mine))
+                        } else {
+                            None
+                        };
+                        *m = next;
+                        (kept, status, out)
+                    })
+                };
+                let (kept, status, out) = match step {
+                    Ok(out) => out,
                     Err(e) => {
-                        error!("Vehicle queue receive error: {:?}", e);
-                        break;
+                        error!("Tank #{} ignores {:?} in {:?}", id, e.event, e.phase);
+                        continue;
+                    }
+                };
+                if let Some(status) = status {
+                    protocol::record(posixmq::STATUS_QUEUE, &status, false);
+                }
+                Self::book(event, kept, &out);
+                for o in out {
+                    match o {
+                        Out::Mine(idx, msg) => mq_m[idx].send(msg).expect("Send fuel request"),
+                        Out::Station(idx, msg) => mq_s
+                            .entry(idx)
+                            .or_insert_with(|| PMQ::open(role.target(idx).queue().as_ref()))
+                            .send(msg)
+                            .expect("Send fuel to station"),
+                        Out::Tank(msg) => mq_v.send(msg).expect("Send move to vehicle"),
+                        Out::Served(idx) => planner.coordinator.lock().unwrap().remove(idx),
                     }
                 }
             }
         });
     }
 
+    /// Book the change of the tank fuel and the fuel of the messages of a step
+    fn book(event: Event, kept: f32, out: &[Out]) {
+        let received = match event {
+            Event::Fuel(amount) | Event::Rejected(_, amount) => amount,
+            Event::Consume(_) => {
+                // an empty tank burns nothing
+                ledger::burn(-kept);
+                return;
+            }
+            _ => 0.0,
+        };
+        let sent: f32 = out
+            .iter()
+            .map(|o| match o {
+                Out::Station(_, Msg::Delivery(_, amount)) => *amount,
+                _ => 0.0,
+            })
+            .sum();
+        if kept != 0.0 || received != 0.0 || sent != 0.0 {
+            ledger::transfer(kept, sent - received);
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
     pub fn get_target(&self) -> Option<usize> {
-        *self.supply_target.lock().unwrap()
+        self.machine.lock().unwrap().target
+    }
+
+    /// The target station followed by the planned stops
+    pub fn get_route(&self) -> Vec<usize> {
+        self.machine.lock().unwrap().stops()
+    }
+
+    pub fn get_mine(&self) -> usize {
+        self.machine.lock().unwrap().mine
+    }
+
+    /// State published by the external tank process
+    pub fn set_status(&self, state: TankState, target: Option<usize>, mine: usize) {
+        self.machine.lock().unwrap().observe(state, target, mine);
+    }
+
+    /// The station picked by hand, `None` on automatic dispatch
+    pub fn get_manual(&self) -> Option<usize> {
+        self.machine.lock().unwrap().manual
     }
 
     pub fn get_state(&self) -> TankState {
-        *self.state.lock().unwrap()
+        self.machine.lock().unwrap().state()
+    }
+
+    pub fn set_strategy(&self, strategy: Box<dyn DispatchStrategy>) {
+        info!("Dispatch strategy: {}", strategy.name());
+        *self.strategy.lock().unwrap() = strategy;
+    }
+
+    pub fn set_mine_policy(&self, policy: MinePolicy) {
+        info!("Tank #{} mine policy: {:?}", self.idx, policy);
+        *self.mine_policy.lock().unwrap() = policy;
+    }
+
+    pub fn set_positions(&self, positions: Positions) {
+        *self.positions.lock().unwrap() = positions;
+    }
+
+    /// Fuel burned on the road
+    pub fn consume(&self, fuel: f32) {
+        let msg = Msg::Consumed(fuel);
+        trace!("Send message: {:?}", msg);
+        self.q.send(msg).expect("Send tank consumed message");
+    }
+
+    /// Send the tank to the station `idx` next
+    pub fn dispatch(&self, idx: usize) {
+        let msg = Msg::Dispatch(idx);
+        trace!("Send message: {:?}", msg);
+        self.q.send(msg).expect("Send tank dispatch message");
     }
 
     pub fn load(&self) {
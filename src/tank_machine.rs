@@ -0,0 +1,871 @@
+use crate::posixmq::Msg;
+use crate::tank::TankState;
+use std::collections::HashSet;
+
+/// Where the tank is in its cycle, the fuel level is kept apart
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phase {
+    /// Driving to the mine
+    Refill,
+    /// Driving to the station
+    Supply,
+    /// Parked at the mine
+    Load,
+    /// Parked at the station
+    Unload,
+}
+
+/// Input of the tank, the messages of its queue it understands
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    /// Fuel from the mine, or returned by a full station
+    Fuel(f32),
+    /// The vehicle arrived at the mine
+    Load,
+    /// The vehicle arrived at the station, or the station took the last portion
+    Unload,
+    /// The mine or the station is done with the tank
+    Move,
+    /// A station asked for fuel
+    Idle(usize),
+    Depleted(usize),
+    /// A broken station returned the delivered fuel
+    Rejected(usize, f32),
+    /// The vehicle burned fuel on the road
+    Consume(f32),
+    /// The station picked by hand
+    Dispatch(usize),
+}
+
+impl Event {
+    pub fn from_msg(msg: Msg) -> Option<Self> {
+        match msg {
+            Msg::Fuel(amount) => Some(Event::Fuel(amount)),
+            Msg::TankLoad => Some(Event::Load),
+            Msg::TankUnload => Some(Event::Unload),
+            Msg::TankMove => Some(Event::Move),
+            Msg::IdleStation(idx, _, _) => Some(Event::Idle(idx)),
+            Msg::MineDepleted(idx) => Some(Event::Depleted(idx)),
+            Msg::Rejected(idx, amount) => Some(Event::Rejected(idx, amount)),
+            Msg::Consumed(fuel) => Some(Event::Consume(fuel)),
+            Msg::Dispatch(idx) => Some(Event::Dispatch(idx)),
+            _ => None,
+        }
+    }
+}
+
+/// Outgoing message of a transition
+#[derive(Clone, Debug, PartialEq)]
+pub enum Out {
+    Mine(usize, Msg),
+    Station(usize, Msg),
+    /// Back to the tank queue
+    Tank(Msg),
+    /// The station request is done with, the coordinator can forget it
+    Served(usize),
+}
+
+/// The event makes no sense in the phase, the tank stays as it was
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Unexpected {
+    pub phase: Phase,
+    pub event: Event,
+}
+
+/// Decisions the tank leaves to the rest of the fleet
+pub trait Planner {
+    /// Claim the next stations to supply, in the order of the visits
+    fn plan_route(&mut self, state: TankState, fuel: f32) -> Vec<usize>;
+    /// The not depleted mine to load at next
+    fn choose_mine(&self, depleted: &HashSet<usize>) -> Option<usize>;
+    /// Claim the station picked by hand instead of the planned ones
+    fn claim(&mut self, station: usize);
+}
+
+/// Tank state without the queues: an event turns it into the next state
+/// and the messages to send
+#[derive(Clone, Debug, PartialEq)]
+pub struct TankMachine {
+    pub id: usize,
+    pub phase: Phase,
+    pub fuel: f32,
+    pub capacity: f32,
+    pub target: Option<usize>,
+    /// Stations to visit after the target
+    pub route: Vec<usize>,
+    /// Fuel left to deliver at the target
+    pub quota: f32,
+    pub mine: usize,
+    pub depleted: HashSet<usize>,
+    /// Station picked by hand, the plans go there until the tank arrives
+    pub manual: Option<usize>,
+    /// Requests the mine did not answer with Move yet
+    pub pending: usize,
+}
+
+impl TankMachine {
+    pub fn new(id: usize, capacity: f32, mine: usize) -> Self {
+        TankMachine {
+            id,
+            phase: Phase::Refill,
+            fuel: 0.0,
+            capacity,
+            target: None,
+            route: Vec::new(),
+            quota: 0.0,
+            mine,
+            depleted: HashSet::new(),
+            manual: None,
+            pending: 0,
+        }
+    }
+
+    pub fn state(&self) -> TankState {
+        let percent = self.fuel / self.capacity * 100.0;
+        match self.phase {
+            Phase::Refill => TankState::Refill(percent),
+            Phase::Supply => TankState::Supply(percent),
+            Phase::Load => TankState::Load(percent),
+            Phase::Unload => TankState::Unload(percent),
+        }
+    }
+
+    /// Take over the state of a tank run elsewhere, it has no route after the target
+    pub fn observe(&mut self, state: TankState, target: Option<usize>, mine: usize) {
+        let (phase, percent) = match state {
+            TankState::Refill(p) => (Phase::Refill, p),
+            TankState::Supply(p) => (Phase::Supply, p),
+            TankState::Load(p) => (Phase::Load, p),
+            TankState::Unload(p) => (Phase::Unload, p),
+        };
+        self.phase = phase;
+        self.fuel = percent / 100.0 * self.capacity;
+        self.target = target;
+        self.route.clear();
+        self.mine = mine;
+        self.pending = 0;
+    }
+
+    /// A delivery is a fourth of the capacity
+    fn portion(&self) -> f32 {
+        self.capacity * 0.25
+    }
+
+    /// The target followed by the rest of the route
+    pub fn stops(&self) -> Vec<usize> {
+        self.target
+            .iter()
+            .chain(self.route.iter())
+            .cloned()
+            .collect()
+    }
+
+    fn plan(&mut self, planner: &mut dyn Planner) {
+        if let Some(idx) = self.manual {
+            planner.claim(idx);
+            self.target = Some(idx);
+            self.route.clear();
+            return;
+        }
+        let mut route = planner.plan_route(self.state(), self.fuel);
+        self.target = if route.is_empty() {
+            None
+        } else {
+            Some(route.remove(0))
+        };
+        self.route = route;
+    }
+
+    pub fn step(
+        &self,
+        event: Event,
+        planner: &mut dyn Planner,
+    ) -> Result<(Self, Vec<Out>), Unexpected> {
+        use self::Phase::*;
+        let mut next = self.clone();
+        let mut out = Vec::new();
+        let unexpected = Err(Unexpected {
+            phase: self.phase,
+            event,
+        });
+        match (self.phase, event) {
+            (Load, Event::Fuel(amount)) => next.fuel += amount,
+            // the mine finishes a transfer started before the tank left
+            (Refill, Event::Fuel(amount)) | (Supply, Event::Fuel(amount)) => next.fuel += amount,
+            (Unload, Event::Fuel(amount)) => match self.target {
+                Some(idx) => {
+                    info!("Remain fuel {} from station #{}", amount, idx);
+                    next.fuel += amount;
+                    out.push(Out::Served(idx));
+                    out.push(Out::Station(idx, Msg::Delivery(self.id, 0.0)));
+                }
+                None => return unexpected,
+            },
+
+            (Refill, Event::Load) => {
+                next.phase = Load;
+                next.pending += 1;
+                out.push(Out::Mine(
+                    self.mine,
+                    Msg::Request(self.id, self.capacity - self.fuel),
+                ));
+            }
+            (Supply, Event::Load) | (Load, Event::Load) | (Unload, Event::Load) => {
+                return unexpected
+            }
+
+            (Supply, Event::Unload) | (Unload, Event::Unload) => match self.target {
+                Some(idx) => {
+                    if self.phase == Supply {
+                        // split the load evenly between the stops left
+                        next.quota = self.fuel / (self.route.len() + 1) as f32;
+                    }
+                    let sub = f32::min(f32::min(self.fuel, self.portion()), next.quota);
+                    next.quota -= sub;
+                    next.fuel = f32::max(self.fuel - sub, 0.0);
+                    next.phase = Unload;
+                    if self.manual == Some(idx) {
+                        next.manual = None;
+                    }
+                    if sub <= 0.0 {
+                        // the station got its share, it is served
+                        out.push(Out::Served(idx));
+                    }
+                    out.push(Out::Station(idx, Msg::Delivery(self.id, sub)));
+                }
+                None => return unexpected,
+            },
+            (Refill, Event::Unload) | (Load, Event::Unload) => return unexpected,
+
+            (Load, Event::Move) => {
+                next.pending = self.pending.saturating_sub(1);
+                // the fuel of the other requests is still coming
+                if next.pending == 0 {
+                    next.leave_mine(planner, &mut out);
+                }
+            }
+            (Unload, Event::Move)
+                if self.fuel > 0.0 && !self.route.is_empty() && self.manual.is_none() =>
+            {
+                next.target = Some(next.route.remove(0));
+                next.phase = Supply;
+                trace!("Next stop {:?}, then {:?}", next.target, next.route);
+            }
+            (Refill, Event::Move) | (Supply, Event::Move) | (Unload, Event::Move) => {
+                if self.phase == Refill {
+                    next.pending = self.pending.saturating_sub(1);
+                }
+                next.plan(planner);
+                next.phase = match next.target {
+                    Some(_) if self.fuel > 0.0 => Supply,
+                    _ => Refill,
+                };
+                if next.phase == Refill {
+                    if let Some(idx) = planner.choose_mine(&self.depleted) {
+                        debug!("Refill from mine #{}", idx);
+                        next.mine = idx;
+                    }
+                }
+                trace!(
+                    "Set next target to {:?} then {:?}, phase={:?}",
+                    next.target,
+                    next.route,
+                    next.phase
+                );
+            }
+
+            (Refill, Event::Idle(idx))
+            | (Supply, Event::Idle(idx))
+            | (Load, Event::Idle(idx))
+            | (Unload, Event::Idle(idx)) => {
+                // the coordinator keeps the request, an idle tank claims a station
+                if self.target.is_none() {
+                    next.plan(planner);
+                    if let Some(station) = next.target {
+                        debug!(
+                            "Tank #{} goes to station #{} for #{}",
+                            self.id, station, idx
+                        );
+                        next.wake(&mut out);
+                    }
+                }
+            }
+
+            (Refill, Event::Dispatch(idx))
+            | (Supply, Event::Dispatch(idx))
+            | (Load, Event::Dispatch(idx))
+            | (Unload, Event::Dispatch(idx)) => {
+                info!("Tank #{} is sent to station #{} by hand", self.id, idx);
+                next.manual = Some(idx);
+                if self.target.is_none() {
+                    next.plan(planner);
+                    next.wake(&mut out);
+                } else if self.phase == Supply {
+                    // turn to the station right away
+                    next.plan(planner);
+                }
+            }
+
+            (Refill, Event::Depleted(idx)) => {
+                next.depleted.insert(idx);
+                if self.mine == idx {
+                    if let Some(other) = planner.choose_mine(&next.depleted) {
+                        info!("Mine #{} is depleted, refill from mine #{}", idx, other);
+                        next.mine = other;
+                    }
+                }
+            }
+            (Supply, Event::Depleted(idx))
+            | (Load, Event::Depleted(idx))
+            | (Unload, Event::Depleted(idx)) => {
+                next.depleted.insert(idx);
+            }
+
+            (Unload, Event::Rejected(idx, amount)) => {
+                warn!("Station #{} is broken, it rejected {} fuel", idx, amount);
+                next.fuel += amount;
+                out.push(Out::Served(idx));
+                // choose the next target
+                out.push(Out::Tank(Msg::TankMove));
+            }
+            (Refill, Event::Rejected(..))
+            | (Supply, Event::Rejected(..))
+            | (Load, Event::Rejected(..)) => return unexpected,
+
+            // an empty tank still gets there
+            (Refill, Event::Consume(fuel))
+            | (Supply, Event::Consume(fuel))
+            | (Load, Event::Consume(fuel))
+            | (Unload, Event::Consume(fuel)) => next.fuel = f32::max(self.fuel - fuel, 0.0),
+        }
+        Ok((next, out))
+    }
+
+    /// Ask the mine for a Move to leave with the new target, unless the
+    /// answer of an earlier request is on its way
+    fn wake(&mut self, out: &mut Vec<Out>) {
+        if self.pending == 0 {
+            self.pending += 1;
+            out.push(Out::Mine(self.mine, Msg::Request(self.id, 0.0)));
+        }
+    }
+
+    /// The mine is done with the tank: load more, switch the mine or go supply
+    fn leave_mine(&mut self, planner: &mut dyn Planner, out: &mut Vec<Out>) {
+        let mut ready = self.fuel >= self.portion();
+        if !ready && self.depleted.contains(&self.mine) {
+            match planner.choose_mine(&self.depleted) {
+                Some(idx) => {
+                    info!("Mine #{} is depleted, refill from mine #{}", self.mine, idx);
+                    self.mine = idx;
+                    self.phase = Phase::Refill;
+                    return;
+                }
+                // deliver the leftovers
+                None if self.fuel > 0.0 => ready = true,
+                None => {
+                    info!("All mines are depleted, the tank stops");
+                    return;
+                }
+            }
+        }
+        if !ready {
+            debug!(
+                "not enough fuel received: val={}, need={}",
+                self.fuel,
+                self.portion()
+            );
+            self.pending += 1;
+            out.push(Out::Mine(
+                self.mine,
+                Msg::Request(self.id, self.capacity - self.fuel),
+            ));
+            return;
+        }
+        if self.target.is_none() || self.manual.is_some() {
+            self.plan(planner);
+        }
+        if self.target.is_some() {
+            self.phase = Phase::Supply;
+        }
+        info!("Supply to station {:?}", self.target);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out the given route and mine
+    struct Fixed {
+        route: Vec<usize>,
+        mine: Option<usize>,
+    }
+
+    impl Planner for Fixed {
+        fn plan_route(&mut self, _state: TankState, _fuel: f32) -> Vec<usize> {
+            self.route.clone()
+        }
+
+        fn choose_mine(&self, _depleted: &HashSet<usize>) -> Option<usize> {
+            self.mine
+        }
+
+        fn claim(&mut self, _station: usize) {}
+    }
+
+    const PHASES: [Phase; 4] = [Phase::Refill, Phase::Supply, Phase::Load, Phase::Unload];
+
+    fn tank(phase: Phase, fuel: f32, target: Option<usize>) -> TankMachine {
+        TankMachine {
+            phase,
+            fuel,
+            target,
+            ..TankMachine::new(7, 20.0, 0)
+        }
+    }
+
+    fn step(
+        tank: &TankMachine,
+        event: Event,
+        station: Option<usize>,
+        mine: Option<usize>,
+    ) -> Result<(TankMachine, Vec<Out>), Unexpected> {
+        route(tank, event, station.into_iter().collect(), mine)
+    }
+
+    fn route(
+        tank: &TankMachine,
+        event: Event,
+        route: Vec<usize>,
+        mine: Option<usize>,
+    ) -> Result<(TankMachine, Vec<Out>), Unexpected> {
+        tank.step(event, &mut Fixed { route, mine })
+    }
+
+    #[test]
+    fn fuel_while_loading_is_added() {
+        let (next, out) =
+            step(&tank(Phase::Load, 5.0, None), Event::Fuel(3.0), None, None).unwrap();
+        assert_eq!(next.phase, Phase::Load);
+        assert_eq!(next.fuel, 8.0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn late_fuel_on_the_road_is_kept() {
+        for phase in [Phase::Refill, Phase::Supply].iter() {
+            let (next, out) = step(&tank(*phase, 5.0, None), Event::Fuel(1.0), None, None).unwrap();
+            assert_eq!(next.phase, *phase);
+            assert_eq!(next.fuel, 6.0);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn fuel_returned_by_a_full_station_ends_the_delivery() {
+        let (next, out) = step(
+            &tank(Phase::Unload, 10.0, Some(2)),
+            Event::Fuel(4.0),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(next.phase, Phase::Unload);
+        assert_eq!(next.fuel, 14.0);
+        assert_eq!(
+            out,
+            vec![Out::Served(2), Out::Station(2, Msg::Delivery(7, 0.0))]
+        );
+    }
+
+    #[test]
+    fn fuel_returned_without_a_station_is_unexpected() {
+        let t = tank(Phase::Unload, 10.0, None);
+        assert_eq!(
+            step(&t, Event::Fuel(4.0), None, None),
+            Err(Unexpected {
+                phase: Phase::Unload,
+                event: Event::Fuel(4.0)
+            })
+        );
+    }
+
+    #[test]
+    fn arrival_at_the_mine_requests_the_missing_fuel() {
+        let (next, out) = step(&tank(Phase::Refill, 5.0, None), Event::Load, None, None).unwrap();
+        assert_eq!(next.phase, Phase::Load);
+        assert_eq!(out, vec![Out::Mine(0, Msg::Request(7, 15.0))]);
+    }
+
+    #[test]
+    fn load_away_from_the_road_to_the_mine_is_unexpected() {
+        for phase in [Phase::Supply, Phase::Load, Phase::Unload].iter() {
+            let t = tank(*phase, 5.0, Some(1));
+            assert!(step(&t, Event::Load, None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn unload_delivers_a_portion() {
+        for phase in [Phase::Supply, Phase::Unload].iter() {
+            let mut t = tank(*phase, 20.0, Some(3));
+            t.quota = 20.0;
+            let (next, out) = step(&t, Event::Unload, None, None).unwrap();
+            assert_eq!(next.phase, Phase::Unload);
+            assert_eq!(next.fuel, 15.0);
+            assert_eq!(next.quota, 15.0);
+            assert_eq!(out, vec![Out::Station(3, Msg::Delivery(7, 5.0))]);
+        }
+    }
+
+    #[test]
+    fn arrival_splits_the_load_between_the_stops() {
+        let mut t = tank(Phase::Supply, 12.0, Some(3));
+        t.route = vec![4, 5];
+        let (next, out) = step(&t, Event::Unload, None, None).unwrap();
+        assert_eq!(next.phase, Phase::Unload);
+        assert_eq!(next.fuel, 8.0);
+        assert_eq!(next.quota, 0.0);
+        assert_eq!(out, vec![Out::Station(3, Msg::Delivery(7, 4.0))]);
+    }
+
+    #[test]
+    fn delivered_share_leaves_the_station() {
+        let mut t = tank(Phase::Unload, 8.0, Some(3));
+        t.route = vec![4];
+        let (next, out) = step(&t, Event::Unload, None, None).unwrap();
+        assert_eq!(next.fuel, 8.0);
+        assert_eq!(
+            out,
+            vec![Out::Served(3), Out::Station(3, Msg::Delivery(7, 0.0))]
+        );
+    }
+
+    #[test]
+    fn move_continues_the_route() {
+        let mut t = tank(Phase::Unload, 8.0, Some(3));
+        t.route = vec![4, 5];
+        let (next, out) = step(&t, Event::Move, Some(1), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(4));
+        assert_eq!(next.route, vec![5]);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn empty_tank_drops_the_rest_of_the_route() {
+        let mut t = tank(Phase::Unload, 0.0, Some(3));
+        t.route = vec![4, 5];
+        let (next, _) = step(&t, Event::Move, None, Some(0)).unwrap();
+        assert_eq!(next.phase, Phase::Refill);
+        assert_eq!(next.target, None);
+        assert!(next.route.is_empty());
+    }
+
+    #[test]
+    fn loaded_tank_plans_a_route() {
+        let (next, out) = route(
+            &tank(Phase::Load, 20.0, None),
+            Event::Move,
+            vec![1, 2],
+            None,
+        )
+        .unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.stops(), vec![1, 2]);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn unload_of_an_empty_tank_leaves_the_station() {
+        let (next, out) = step(
+            &tank(Phase::Unload, 0.0, Some(3)),
+            Event::Unload,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(next.fuel, 0.0);
+        assert_eq!(
+            out,
+            vec![Out::Served(3), Out::Station(3, Msg::Delivery(7, 0.0))]
+        );
+    }
+
+    #[test]
+    fn unload_without_a_station_is_unexpected() {
+        for phase in PHASES.iter() {
+            assert!(step(&tank(*phase, 20.0, None), Event::Unload, None, None).is_err());
+        }
+        for phase in [Phase::Refill, Phase::Load].iter() {
+            assert!(step(&tank(*phase, 20.0, Some(1)), Event::Unload, None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn loaded_tank_leaves_the_mine_for_a_station() {
+        let (next, out) = step(&tank(Phase::Load, 20.0, None), Event::Move, Some(1), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(1));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn loaded_tank_keeps_its_target() {
+        let (next, _) = step(
+            &tank(Phase::Load, 20.0, Some(2)),
+            Event::Move,
+            Some(1),
+            None,
+        )
+        .unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(2));
+    }
+
+    #[test]
+    fn loaded_tank_waits_at_the_mine_without_requests() {
+        let (next, out) = step(&tank(Phase::Load, 20.0, None), Event::Move, None, None).unwrap();
+        assert_eq!(next.phase, Phase::Load);
+        assert_eq!(next.target, None);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn underloaded_tank_asks_the_mine_again() {
+        let (next, out) =
+            step(&tank(Phase::Load, 2.0, Some(1)), Event::Move, Some(1), None).unwrap();
+        assert_eq!(next.phase, Phase::Load);
+        assert_eq!(out, vec![Out::Mine(0, Msg::Request(7, 18.0))]);
+    }
+
+    #[test]
+    fn underloaded_tank_switches_from_a_depleted_mine() {
+        let mut t = tank(Phase::Load, 2.0, None);
+        t.depleted.insert(0);
+        let (next, out) = step(&t, Event::Move, None, Some(1)).unwrap();
+        assert_eq!(next.phase, Phase::Refill);
+        assert_eq!(next.mine, 1);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn underloaded_tank_delivers_leftovers_when_all_mines_are_depleted() {
+        let mut t = tank(Phase::Load, 2.0, None);
+        t.depleted.insert(0);
+        let (next, out) = step(&t, Event::Move, Some(3), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(3));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn empty_tank_stops_when_all_mines_are_depleted() {
+        let mut t = tank(Phase::Load, 0.0, None);
+        t.depleted.insert(0);
+        let (next, out) = step(&t, Event::Move, Some(3), None).unwrap();
+        assert_eq!(next, t);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn move_goes_to_the_next_station_with_fuel() {
+        for phase in [Phase::Refill, Phase::Supply, Phase::Unload].iter() {
+            let (next, out) =
+                step(&tank(*phase, 10.0, Some(0)), Event::Move, Some(2), Some(1)).unwrap();
+            assert_eq!(next.phase, Phase::Supply);
+            assert_eq!(next.target, Some(2));
+            assert_eq!(next.mine, 0);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn move_goes_to_the_fullest_mine_without_fuel_or_stations() {
+        for phase in [Phase::Refill, Phase::Supply, Phase::Unload].iter() {
+            let (next, _) =
+                step(&tank(*phase, 0.0, Some(0)), Event::Move, Some(2), Some(1)).unwrap();
+            assert_eq!(next.phase, Phase::Refill);
+            assert_eq!(next.target, Some(2));
+            assert_eq!(next.mine, 1);
+
+            let (next, _) = step(&tank(*phase, 10.0, Some(0)), Event::Move, None, Some(1)).unwrap();
+            assert_eq!(next.phase, Phase::Refill);
+            assert_eq!(next.target, None);
+            assert_eq!(next.mine, 1);
+        }
+    }
+
+    #[test]
+    fn idle_tank_claims_a_station() {
+        for phase in PHASES.iter() {
+            let (next, out) =
+                step(&tank(*phase, 10.0, None), Event::Idle(4), Some(4), None).unwrap();
+            assert_eq!(next.phase, *phase);
+            assert_eq!(next.target, Some(4));
+            assert_eq!(out, vec![Out::Mine(0, Msg::Request(7, 0.0))]);
+        }
+    }
+
+    #[test]
+    fn loading_tank_leaves_after_the_last_answer_of_the_mine() {
+        let mut t = tank(Phase::Load, 10.0, None);
+        t.pending = 1;
+        let (next, out) = step(&t, Event::Idle(4), Some(4), None).unwrap();
+        assert_eq!(next.target, Some(4));
+        assert!(out.is_empty());
+        let (next, _) = step(&next, Event::Fuel(5.0), Some(4), None).unwrap();
+        let (next, out) = step(&next, Event::Move, Some(4), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.pending, 0);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn busy_tank_ignores_station_requests() {
+        for phase in PHASES.iter() {
+            let t = tank(*phase, 10.0, Some(1));
+            let (next, out) = step(&t, Event::Idle(4), Some(4), None).unwrap();
+            assert_eq!(next, t);
+            assert!(out.is_empty());
+        }
+    }
+
+    #[test]
+    fn idle_tank_without_a_claim_stays_idle() {
+        let t = tank(Phase::Load, 10.0, None);
+        let (next, out) = step(&t, Event::Idle(4), None, None).unwrap();
+        assert_eq!(next, t);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn depleted_mine_is_remembered() {
+        for phase in PHASES.iter() {
+            let (next, _) =
+                step(&tank(*phase, 10.0, None), Event::Depleted(1), None, None).unwrap();
+            assert!(next.depleted.contains(&1));
+            assert_eq!(next.mine, 0);
+        }
+    }
+
+    #[test]
+    fn depleted_mine_on_the_way_is_replaced() {
+        let (next, out) = step(
+            &tank(Phase::Refill, 0.0, None),
+            Event::Depleted(0),
+            None,
+            Some(1),
+        )
+        .unwrap();
+        assert_eq!(next.phase, Phase::Refill);
+        assert_eq!(next.mine, 1);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn rejected_fuel_is_taken_back() {
+        let (next, out) = step(
+            &tank(Phase::Unload, 10.0, Some(2)),
+            Event::Rejected(2, 5.0),
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(next.phase, Phase::Unload);
+        assert_eq!(next.fuel, 15.0);
+        assert_eq!(out, vec![Out::Served(2), Out::Tank(Msg::TankMove)]);
+    }
+
+    #[test]
+    fn rejected_away_from_a_station_is_unexpected() {
+        for phase in [Phase::Refill, Phase::Supply, Phase::Load].iter() {
+            let t = tank(*phase, 10.0, Some(2));
+            assert!(step(&t, Event::Rejected(2, 5.0), None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn road_consumption_is_deducted() {
+        for phase in PHASES.iter() {
+            let t = tank(*phase, 3.0, Some(1));
+            let (next, out) = step(&t, Event::Consume(1.0), None, None).unwrap();
+            assert_eq!(next.phase, *phase);
+            assert_eq!(next.fuel, 2.0);
+            assert!(out.is_empty());
+            let (next, _) = step(&t, Event::Consume(5.0), None, None).unwrap();
+            assert_eq!(next.fuel, 0.0);
+        }
+    }
+
+    #[test]
+    fn dispatch_turns_a_supplying_tank() {
+        let t = tank(Phase::Supply, 10.0, Some(1));
+        let (next, out) = step(&t, Event::Dispatch(3), Some(2), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(3));
+        assert_eq!(next.manual, Some(3));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn dispatch_wakes_an_idle_tank() {
+        let t = tank(Phase::Load, 20.0, None);
+        let (next, out) = step(&t, Event::Dispatch(2), None, None).unwrap();
+        assert_eq!(next.target, Some(2));
+        assert_eq!(out, vec![Out::Mine(0, Msg::Request(7, 0.0))]);
+    }
+
+    #[test]
+    fn dispatch_overrides_the_rest_of_the_route() {
+        let mut t = tank(Phase::Unload, 10.0, Some(1));
+        t.route = vec![4];
+        let (next, _) = step(&t, Event::Dispatch(2), Some(5), None).unwrap();
+        assert_eq!(next.target, Some(1));
+        let (next, _) = step(&next, Event::Move, Some(5), None).unwrap();
+        assert_eq!(next.phase, Phase::Supply);
+        assert_eq!(next.target, Some(2));
+        assert!(next.route.is_empty());
+    }
+
+    #[test]
+    fn arrival_ends_the_dispatch() {
+        let mut t = tank(Phase::Supply, 10.0, Some(2));
+        t.manual = Some(2);
+        let (next, _) = step(&t, Event::Unload, None, None).unwrap();
+        assert_eq!(next.phase, Phase::Unload);
+        assert_eq!(next.manual, None);
+    }
+
+    #[test]
+    fn every_tank_message_is_an_event() {
+        let msgs = vec![
+            Msg::Fuel(1.0),
+            Msg::TankLoad,
+            Msg::TankUnload,
+            Msg::TankMove,
+            Msg::IdleStation(1, 2.0, 3.0),
+            Msg::MineDepleted(1),
+            Msg::Rejected(1, 2.0),
+            Msg::Consumed(1.0),
+            Msg::Dispatch(1),
+        ];
+        for msg in msgs {
+            assert!(Event::from_msg(msg).is_some());
+        }
+        assert_eq!(Event::from_msg(Msg::FollowProfile), None);
+    }
+
+    #[test]
+    fn observed_state_replaces_the_route() {
+        let mut t = tank(Phase::Supply, 20.0, Some(1));
+        t.route = vec![2];
+        t.observe(TankState::Unload(25.0), Some(3), 1);
+        assert_eq!(t.phase, Phase::Unload);
+        assert_eq!(t.fuel, 5.0);
+        assert_eq!(t.target, Some(3));
+        assert!(t.route.is_empty());
+        assert_eq!(t.mine, 1);
+    }
+}
@@ -1,49 +1,144 @@
-use crate::model::Ids;
+use crate::dispatch::{self, MinePolicy, Positions};
+use crate::fleet::Coordinator;
+use crate::map::{Leg, Map, Node, WORLD_WIDTH};
+use crate::mine::MineStore;
+use crate::scenario::FleetConfig;
 use crate::tank::*;
 use nannou::prelude::*;
 use nannou::ui::prelude::*;
 use nannou::Draw;
+use std::mem::{self, Discriminant};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[derive(PartialEq)]
-pub struct RoutePoint {
-    id: widget::Id,
-    p: Point2,
+// gap between the dots of the planned route
+const ROUTE_DOT_STEP: f32 = 8.0;
+// the player loads and unloads within this distance (km) of the parking point
+const PLAYER_REACH: f32 = 4.0;
+
+/// Cost of the trips of a vehicle
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TripStats {
+    pub trips: u32,
+    pub distance: f32,
+    pub time: Duration,
+    pub fuel: f32,
+    /// Distance, time and fuel of the last trip
+    pub last: Option<(f32, Duration, f32)>,
 }
 
-impl RoutePoint {
-    fn new(id: widget::Id) -> Self {
-        RoutePoint {
-            id,
-            p: pt2(0.0, 0.0),
+impl TripStats {
+    fn record(&mut self, distance: f32, time: Duration, fuel: f32) {
+        self.trips += 1;
+        self.distance += distance;
+        self.time += time;
+        self.fuel += fuel;
+        self.last = Some((distance, time, fuel));
+    }
+
+    pub fn add(&mut self, other: &TripStats) {
+        self.trips += other.trips;
+        self.distance += other.distance;
+        self.time += other.time;
+        self.fuel += other.fuel;
+        if other.last.is_some() {
+            self.last = other.last;
         }
     }
 }
 
 pub struct Vehicle {
+    idx: usize,
     pub wh: Point2,
-    mine: RoutePoint,
-    station: RoutePoint,
-    ids: Ids,
+    map: Arc<Mutex<Map>>,
     tank: Tank,
-    position: Option<Point2>,
-    pub need_resize: bool,
+    /// Position in world units
+    world: Option<Point2>,
+    /// The node the vehicle left or is parked at
+    at: Node,
+    /// The vehicle is at the parking point of `at` or drives to it
+    parked: bool,
+    /// The road the vehicle is on and the node it leads to
+    leg: Option<(usize, Node)>,
+    /// The node the vehicle drives to along the `route`
+    heading: Option<Node>,
+    /// Roads left after the current one
+    route: Vec<Leg>,
+    /// Map version the route was planned on
+    version: u64,
+    /// World points left to drive through on the current road or to the parking point
+    path: Vec<Point2>,
+    /// World points of the roads through the stops left after the current one
+    planned: Vec<Point2>,
+    /// Pixels per world unit
+    scale: f32,
+    last_update: Instant,
+    /// Distance driven and the start of the current trip
+    trip: Option<(f32, Instant)>,
+    /// The state in which the vehicle arrived, it waits for the tank to take it over
+    arrived: Option<Discriminant<TankState>>,
+    /// Fuel burned per world unit of distance
+    consumption: f32,
+    control: Control,
+    /// Direction the player steers to, zero when no key is held
+    steer: Vector2,
+    /// Station the player unloads at as soon as the tank heads there
+    unload_at: Option<usize>,
+    pub stats: TripStats,
 }
 
 impl Vehicle {
-    pub fn new(ids: Ids) -> Self {
+    pub fn new(
+        idx: usize,
+        role: Role,
+        control: Control,
+        fleet: &FleetConfig,
+        map: Arc<Mutex<Map>>,
+        mines: Vec<MineStore>,
+        coordinator: Arc<Mutex<Coordinator>>,
+    ) -> Self {
+        let targets = {
+            let map = map.lock().unwrap();
+            match role {
+                Role::LongHaul => map.depots.len(),
+                _ => map.stations.len(),
+            }
+        };
+        let tank = Tank::new(idx, role, control, fleet.stops, mines, targets, coordinator);
         Vehicle {
+            idx,
             wh: pt2(50.0, 20.0),
-            mine: RoutePoint::new(ids.mine),
-            station: RoutePoint::new(ids.stations[0]),
-            ids: ids,
-            tank: Tank::new(),
-            position: None,
-            need_resize: true,
+            map,
+            at: role.source(tank.get_mine()),
+            parked: true,
+            leg: None,
+            tank,
+            world: None,
+            heading: None,
+            route: Vec::new(),
+            version: 0,
+            path: Vec::new(),
+            planned: Vec::new(),
+            scale: 1.0,
+            last_update: Instant::now(),
+            trip: None,
+            arrived: None,
+            consumption: fleet.consumption,
+            control,
+            steer: vec2(0.0, 0.0),
+            unload_at: None,
+            stats: TripStats::default(),
         }
     }
 
+    /// Position on the screen
+    fn position(&self) -> Option<Point2> {
+        self.world.map(|w| w * self.scale)
+    }
+
     pub fn draw(&self, draw: &Draw) {
-        if let Some(pos) = self.position {
+        if let Some(pos) = self.position() {
+            self.draw_route(draw, pos);
             draw.rect().wh(self.wh).color(GREEN).xy(pos);
             let transfer_wh = pt2(self.wh.x, self.wh.y * (1.0 - self.fuel_percent() / 100.0));
             draw.rect()
@@ -53,61 +148,286 @@ impl Vehicle {
         }
     }
 
-    pub fn update_route(&mut self, ui: &mut UiCell, state: TankState) {
-        if let Some(r) = ui.rect_of(self.ids.mine) {
-            self.mine.p = pt2(
-                r.x.end as f32 + self.wh.x / 2.0,
-                r.y.start as f32 + self.wh.y / 2.0,
-            )
+    /// Dotted line along the roads through the stops left
+    fn draw_route(&self, draw: &Draw, pos: Point2) {
+        let mut points = vec![pos];
+        points.extend(self.planned.iter().map(|p| *p * self.scale));
+        for leg in points.windows(2) {
+            let (from, to) = (leg[0], leg[1]);
+            let dots = (from.distance(to) / ROUTE_DOT_STEP) as usize;
+            for i in 1..dots {
+                let p = from.lerp(to, i as f32 / dots as f32);
+                draw.ellipse().xy(p).w_h(3.0, 3.0).color(GREEN);
+            }
         }
+    }
 
-        if let Some(r) = ui.rect_of(self.station.id) {
-            self.station.p = pt2(
-                r.x.end as f32 - self.wh.x / 2.0,
-                r.y.start as f32 - self.wh.y,
-            )
-        }
+    /// World point where the vehicle waits at the `node`, the tanks of the fleet
+    /// park next to each other right of the mine or depot and below the station
+    fn parking(&self, map: &Map, node: Node) -> Point2 {
+        let offset = self.idx as f32 * (self.wh.y + 2.0);
+        let p = match node {
+            Node::Mine(_) | Node::Depot(_) => pt2(self.wh.x / 2.0, self.wh.y / 2.0 + offset),
+            Node::Station(_) => pt2(-self.wh.x / 2.0, -self.wh.y - offset),
+        };
+        map.position(node) + p / self.scale
+    }
 
+    /// The node the tank needs to be at in the `state`
+    fn destination(&self, state: TankState) -> Option<Node> {
+        let role = self.tank.role();
         match state {
-            TankState::Load(_) => {
-                self.position = Some(self.mine.p);
+            TankState::Refill(_) | TankState::Load(_) => Some(role.source(self.tank.get_mine())),
+            TankState::Supply(_) | TankState::Unload(_) => {
+                self.tank.get_target().map(|idx| role.target(idx))
+            }
+        }
+    }
+
+    /// Search the way to the destination when it changes, a road opens or closes,
+    /// or the last search found none. A vehicle already on a road drives it to the end.
+    fn update_route(&mut self, map: &Map, dest: Node, state: TankState) {
+        let stale = self.heading != Some(dest) || self.version != map.version;
+        let start = self.leg.map(|(_, node)| node).unwrap_or(self.at);
+        if stale || (self.route.is_empty() && start != dest) {
+            self.heading = Some(dest);
+            self.version = map.version;
+            self.route = match map.route(start, dest) {
+                Some(route) => route,
+                None => {
+                    if stale {
+                        warn!(
+                            "Vehicle #{} finds no way from {} to {}",
+                            self.idx, start, dest
+                        );
+                    }
+                    Vec::new()
+                }
+            };
+        }
+
+        let role = self.tank.role();
+        let mut last = dest;
+        self.planned = self.path.clone();
+        for leg in self.route.iter() {
+            self.planned.extend(leg.points.iter().cloned());
+        }
+        let mut stops = self.tank.get_route();
+        if let TankState::Supply(_) | TankState::Unload(_) = state {
+            stops.retain(|idx| role.target(*idx) != dest);
+        }
+        for idx in stops {
+            let next = role.target(idx);
+            for leg in map.route(last, next).unwrap_or_default() {
+                self.planned.extend(leg.points);
             }
-            TankState::Unload(_) => {
-                self.position = Some(self.station.p);
+            last = next;
+        }
+    }
+
+    /// Drive the `step` along the path, entering the roads of the route one by one,
+    /// returns the distance driven and whether the vehicle is parked at the `dest`
+    fn drive(&mut self, map: &mut Map, dest: Node, mut step: f32) -> (f32, bool) {
+        let mut p = self.world.unwrap();
+        let mut distance = 0.0;
+        loop {
+            if let Some(&to) = self.path.first() {
+                let left = p.distance(to);
+                if left > step {
+                    p += (to - p) / left * step;
+                    distance += step;
+                    break;
+                }
+                p = to;
+                step -= left;
+                distance += left;
+                self.path.remove(0);
+                continue;
             }
-            _ => (),
+            if let Some((road, node)) = self.leg.take() {
+                map.leave(road);
+                self.at = node;
+            }
+            if self.at == dest {
+                if self.parked {
+                    self.world = Some(p);
+                    return (distance, true);
+                }
+                self.parked = true;
+                self.path.push(self.parking(map, dest));
+                continue;
+            }
+            // wait at the node for a free road
+            if self.route.is_empty() || !map.enter(self.route[0].road) {
+                break;
+            }
+            let leg = self.route.remove(0);
+            self.parked = false;
+            self.leg = Some((leg.road, leg.to));
+            self.path = leg.points;
         }
-        self.need_resize = false;
+        self.world = Some(p);
+        (distance, false)
     }
 
+    /// Drive towards the target at `speed` world units per second
     pub fn update(&mut self, ui: &mut UiCell, speed: f32) {
-        if let Some(id) = self.current_station() {
-            self.station.id = id;
-        }
+        let dt = self.last_update.elapsed();
+        self.last_update = Instant::now();
+        let dt = dt.as_secs() as f32 + dt.subsec_micros() as f32 / 1e6;
+        self.scale = ui.win_w as f32 / WORLD_WIDTH;
+
+        let shared = self.map.clone();
+        let mut map = shared.lock().unwrap();
         let state = self.tank.get_state();
-        self.update_route(ui, state);
+        let stations = match self.tank.role() {
+            Role::LongHaul => &map.depots,
+            _ => &map.stations,
+        };
+        let sources = match self.tank.role() {
+            Role::Local => &map.depots,
+            _ => &map.mines,
+        };
+        self.tank.set_positions(Positions {
+            tank: self.world,
+            stations: stations.clone(),
+            sources: sources.clone(),
+        });
+        if self.world.is_none() {
+            self.world = Some(self.parking(&map, self.at));
+        }
+        if self.control == Control::Player {
+            self.drive_player(ui, &map, state, speed * dt);
+            return;
+        }
+        let dest = match self.destination(state) {
+            Some(dest) => dest,
+            None => return,
+        };
+        self.update_route(&map, dest, state);
 
-        if let Some(p) = self.position {
-            let (from, to) = match state {
-                TankState::Load(_) | TankState::Unload(_) => return,
-                TankState::Supply(_) => (&self.mine, &self.station),
-                TankState::Refill(_) => (&self.station, &self.mine),
-            };
-            let dist = from.p.distance(to.p);
-
-            let speedup = speed * (dist / p.distance(to.p)) / 3.0;
-            let mut new_p = p.lerp(to.p, speed + speedup);
-            if p.distance(new_p) >= p.distance(to.p) {
-                new_p = to.p; // complete move
-                match state {
-                    TankState::Refill(_) => self.tank.load(),
-                    TankState::Supply(_) => self.tank.unload(),
-                    _ => (),
-                };
+        if let TankState::Load(_) | TankState::Unload(_) = state {
+            self.arrived = None;
+            return;
+        }
+        if self.arrived == Some(mem::discriminant(&state)) {
+            return;
+        }
+        let (mut distance, start) = self.trip.unwrap_or((0.0, Instant::now()));
+        let (driven, done) = self.drive(&mut map, dest, speed * dt);
+        distance += driven;
+        if !done {
+            self.trip = Some((distance, start));
+            return;
+        }
+        // complete move
+        self.trip = Some((distance, start));
+        self.finish_trip();
+        self.arrived = Some(mem::discriminant(&state));
+        match state {
+            TankState::Refill(_) => self.tank.load(),
+            TankState::Supply(_) => self.tank.unload(),
+            _ => (),
+        };
+    }
+
+    /// Record the trip and burn its fuel
+    fn finish_trip(&mut self) {
+        if let Some((distance, start)) = self.trip.take() {
+            let fuel = distance * self.consumption;
+            self.stats.record(distance, start.elapsed(), fuel);
+            if fuel > 0.0 {
+                self.tank.consume(fuel);
+            }
+        }
+    }
+
+    /// Steer the player's vehicle, a zero `direction` stops it
+    pub fn steer(&mut self, direction: Vector2) {
+        self.steer = direction;
+    }
+
+    /// Drive by hand within the window, the tank follows the keys the player presses
+    fn drive_player(&mut self, ui: &UiCell, map: &Map, state: TankState, step: f32) {
+        // show the way to the mine to load at
+        self.planned = match state {
+            TankState::Refill(_) => {
+                let mine = self.tank.role().source(self.tank.get_mine());
+                vec![self.parking(map, mine)]
+            }
+            _ => Vec::new(),
+        };
+        let mut p = self.world.unwrap();
+        let length = (self.steer.x * self.steer.x + self.steer.y * self.steer.y).sqrt();
+        if length > 0.0 {
+            let half = vec2(ui.win_w as f32, ui.win_h as f32) / self.scale / 2.0;
+            let to = p + self.steer / length * step;
+            let to = pt2(to.x.clamp(-half.x, half.x), to.y.clamp(-half.y, half.y));
+            let (distance, start) = self.trip.unwrap_or((0.0, Instant::now()));
+            self.trip = Some((distance + p.distance(to), start));
+            p = to;
+        }
+        self.world = Some(p);
+        if let (Some(idx), TankState::Supply(_)) = (self.unload_at, state) {
+            if self.tank.get_target() == Some(idx) {
+                self.unload_at = None;
+                self.tank.unload();
+            }
+        }
+    }
+
+    /// The player loads at the mine the tank heads to
+    pub fn player_load(&mut self) {
+        let shared = self.map.clone();
+        let map = shared.lock().unwrap();
+        let mine = self.tank.role().source(self.tank.get_mine());
+        let parking = self.parking(&map, mine);
+        match (self.world, self.tank.get_state()) {
+            (Some(p), TankState::Refill(_)) if p.distance(parking) <= PLAYER_REACH => {
+                self.world = Some(parking);
+                self.at = mine;
+                self.finish_trip();
+                self.tank.load();
+            }
+            (_, TankState::Refill(_)) => info!("Drive to {} to load", mine),
+            (_, state) => info!("Can't load in {:?}", state),
+        }
+    }
+
+    /// The player unloads at the nearest station, the tank goes there by hand
+    pub fn player_unload(&mut self) {
+        let shared = self.map.clone();
+        let map = shared.lock().unwrap();
+        let role = self.tank.role();
+        let p = match self.world {
+            Some(p) => p,
+            None => return,
+        };
+        let nearest = (0..map.stations.len())
+            .map(|idx| (idx, self.parking(&map, role.target(idx))))
+            .filter(|(_, parking)| p.distance(*parking) <= PLAYER_REACH)
+            .min_by(|a, b| p.distance(a.1).total_cmp(&p.distance(b.1)));
+        let (idx, parking) = match nearest {
+            Some(near) if self.fuel_percent() > 0.0 => near,
+            Some(_) => {
+                info!("The tank is empty");
+                return;
+            }
+            None => {
+                info!("Drive to a station to unload");
+                return;
+            }
+        };
+        self.world = Some(parking);
+        self.at = role.target(idx);
+        self.finish_trip();
+        match self.tank.get_state() {
+            TankState::Supply(_) if self.tank.get_target() == Some(idx) => self.tank.unload(),
+            TankState::Unload(_) => info!("The tank is unloading"),
+            _ => {
+                self.tank.dispatch(idx);
+                self.unload_at = Some(idx);
             }
-            self.position = Some(new_p);
-        } else {
-            self.position = Some(self.mine.p);
         }
     }
 
@@ -120,14 +440,46 @@ impl Vehicle {
         }
     }
 
-    fn current_station(&self) -> Option<widget::Id> {
-        match self.tank.get_target() {
-            Some(idx) => Some(self.ids.stations[idx]),
-            None => None,
-        }
+    /// Tank index in the fleet
+    pub fn idx(&self) -> usize {
+        self.idx
+    }
+
+    pub fn control(&self) -> Control {
+        self.control
+    }
+
+    /// State published by the external tank process
+    pub fn set_status(&self, state: TankState, target: Option<usize>, mine: usize) {
+        self.tank.set_status(state, target, mine);
+    }
+
+    /// Position in world units
+    pub fn world(&self) -> Option<Point2> {
+        self.world
+    }
+
+    pub fn role(&self) -> Role {
+        self.tank.role()
+    }
+
+    /// Go to the station `idx` next, over the dispatch strategy
+    pub fn dispatch(&self, idx: usize) {
+        self.tank.dispatch(idx);
+    }
+
+    /// The station picked by hand, `None` on automatic dispatch
+    pub fn manual(&self) -> Option<usize> {
+        self.tank.get_manual()
+    }
+
+    /// Switch to the strategy with the index in `dispatch::STRATEGIES`
+    pub fn set_strategy(&mut self, idx: usize) {
+        self.tank.set_strategy(dispatch::by_index(idx));
     }
 
-    pub fn resize(&mut self) {
-        self.need_resize = true;
+    /// Switch to the mine choice with the index in `dispatch::MINE_POLICIES`
+    pub fn set_mine_policy(&mut self, idx: usize) {
+        self.tank.set_mine_policy(MinePolicy::by_index(idx));
     }
 }